@@ -7,10 +7,11 @@ use std::error::Error;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 
-use i3_focus_last::utils;
-use i3_focus_last::{WindowsSortStyle, get_windows_by_history};
+use i3_focus_last::layout::Layout;
+use i3_focus_last::{Scope, WindowsSortStyle, get_windows_by_history};
+use i3_focus_last::{rules, utils};
 
-use rofi::helpers::{find_arg_bool, rofi_view_hide, token_match_pattern};
+use rofi::helpers::{find_arg_bool, find_arg_str, rofi_view_hide, token_match_pattern};
 use rofi::{CRofiMode, EntryStateFlags, MenuReturn, ModeMode, ModeType, Pattern, RofiMode};
 
 #[macro_use]
@@ -19,6 +20,7 @@ extern crate byte_strings;
 struct Mode {
     conn: RefCell<swayipc::Connection>,
     windows: Vec<swayipc::Node>,
+    layout: Layout,
     icons_map: HashMap<String, String>,
 }
 
@@ -29,19 +31,37 @@ impl RofiMode for Mode {
     const NAME_KEY: &'static [c_char; 128] = rofi_name_key!(b"display-windowi3");
 
     fn init() -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let sort_style = if find_arg_bool("-window-focused-first") {
+        let sort_style = if find_arg_bool("-window-predicted") {
+            WindowsSortStyle::Predicted
+        } else if find_arg_bool("-window-frecency") {
+            WindowsSortStyle::Frecency
+        } else if find_arg_bool("-window-focused-first") {
             WindowsSortStyle::CurrentFirst
         } else {
             WindowsSortStyle::CurrentLast
         };
 
+        let scope = match find_arg_str("-window-scope") {
+            Some(s) => s.parse()?,
+            None => Scope::Global,
+        };
+
         let mut conn = swayipc::Connection::new()?;
-        let windows = get_windows_by_history(&mut conn, sort_style)?;
+        let rules = rules::read_exclude_rules(None);
+        let mut windows = get_windows_by_history(&mut conn, sort_style, scope, &rules)?;
+        let layout = Layout::from_tree(&conn.get_tree()?);
+        if find_arg_bool("-window-skip-visible") {
+            windows.retain(|w| !layout.is_visible(w.id));
+        }
+        if find_arg_bool("-window-urgent-first") {
+            utils::urgent_first(&mut windows);
+        }
         let icons_map = utils::read_icons_map(None);
 
         Ok(Mode {
             conn: RefCell::new(conn),
             windows,
+            layout,
             icons_map,
         })
     }
@@ -56,7 +76,7 @@ impl RofiMode for Mode {
         let win = &self.windows[selected_line];
 
         Some((
-            utils::window_format_line(win, None),
+            utils::window_format_line(win, None, self.layout.in_scratchpad(win.id), None),
             EntryStateFlags::Markup,
         ))
     }
@@ -72,7 +92,10 @@ impl RofiMode for Mode {
             let win = &self.windows[selected_line];
             self.conn
                 .borrow_mut()
-                .run_command(format!("[con_id={}] focus", win.id).as_str())
+                .run_command(utils::focus_command(
+                    win.id,
+                    self.layout.in_scratchpad(win.id),
+                ))
                 .unwrap();
         }
 
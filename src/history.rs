@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::FocusStats;
+use crate::state::{self, SavedWindow, WindowIdentity};
+use crate::{extract_windows, utils};
+
+static BUFFER_SIZE: usize = 100;
+
+/// Time after which the weight of a window in the frecency ranking is halved
+static FRECENCY_HALF_LIFE_MS: f64 = 6. * 3600. * 1000.;
+
+/// Applications kept as destinations of each application, the least
+/// frequent ones are forgotten first
+static TRANSITIONS_PER_APP: usize = 20;
+
+/// The counts of an application are halved when one of them reaches this, so
+/// that old habits fade
+static TRANSITIONS_MAX_COUNT: u32 = 1000;
+
+/// Number of times the focus went from an application to another, by
+/// app_id or X11 class
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Transitions(HashMap<String, HashMap<String, u32>>);
+
+impl Transitions {
+    fn record(&mut self, from: &str, to: &str) {
+        let counts = self.0.entry(from.to_string()).or_default();
+        let count = counts.entry(to.to_string()).or_default();
+        *count += 1;
+
+        if *count >= TRANSITIONS_MAX_COUNT {
+            for c in counts.values_mut() {
+                *c /= 2;
+            }
+            counts.retain(|_, c| *c > 0);
+        }
+
+        if counts.len() > TRANSITIONS_PER_APP
+            && let Some(rarest) = counts
+                .iter()
+                .filter(|(app, _)| *app != to)
+                .min_by_key(|(_, c)| **c)
+                .map(|(app, _)| app.clone())
+        {
+            counts.remove(&rarest);
+        }
+    }
+
+    fn count(&self, from: &str, to: &str) -> u32 {
+        self.0
+            .get(from)
+            .and_then(|m| m.get(to))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Focus history maintained by the server
+pub struct History {
+    /// Window ids, most recently focused first
+    pub windows: VecDeque<i64>,
+    /// Whether the focused window is not the first of `windows` (after a
+    /// window was closed or on an empty workspace)
+    pub empty_focus: bool,
+    /// Workspaces (con_id and name), most recently focused first
+    pub workspaces: VecDeque<(i64, String)>,
+    idents: HashMap<i64, WindowIdentity>,
+    stats: HashMap<i64, FocusStats>,
+    /// Window holding the focus and since when
+    focused: Option<(i64, Instant)>,
+    pub transitions: Transitions,
+    /// Whether transitions were recorded since the last save
+    transitions_changed: bool,
+}
+
+/// Follow the focus down to the focused workspace
+fn focused_workspace(root: &swayipc::Node) -> Option<&swayipc::Node> {
+    let mut node = root;
+
+    while node.node_type != swayipc::NodeType::Workspace {
+        let fid = node.focus.first()?;
+        node = node.nodes.iter().find(|n| n.id == *fid)?;
+    }
+    Some(node)
+}
+
+/// Weight of a window in the frecency ranking at `now`
+fn frecency_score(stats: &FocusStats, now: u64) -> f64 {
+    let weight = stats.focus_count as f64 + stats.focused_ms as f64 / 60000.;
+    let age = now.saturating_sub(stats.last_focused) as f64;
+    weight * (-age / FRECENCY_HALF_LIFE_MS).exp2()
+}
+
+impl History {
+    /// Build the history from the current tree
+    ///
+    /// `saved` entries are matched to the live windows, using X11 window ids
+    /// when the con_ids have changed (after an i3 restart for example). The
+    /// focused window is put in front.
+    pub fn from_tree(tree: &swayipc::Node, saved: &[SavedWindow]) -> History {
+        let live_windows = extract_windows(tree);
+        let idents = live_windows
+            .values()
+            .map(|n| (n.id, WindowIdentity::from_node(n)))
+            .collect();
+
+        let saved_idents: Vec<_> = saved.iter().map(|s| &s.ident).collect();
+        let matched = state::match_windows(&saved_idents, &live_windows);
+
+        let mut hist = History {
+            windows: matched.iter().flatten().copied().collect(),
+            empty_focus: true,
+            workspaces: VecDeque::new(),
+            idents,
+            stats: matched
+                .iter()
+                .zip(saved)
+                .filter_map(|(m, s)| m.map(|wid| (wid, s.stats.clone())))
+                .collect(),
+            focused: None,
+            transitions: Transitions::default(),
+            transitions_changed: false,
+        };
+        hist.windows.truncate(BUFFER_SIZE);
+
+        if let Ok(wid) = utils::get_focused_window(tree) {
+            hist.promote(wid);
+            hist.focused = Some((wid, Instant::now()));
+        }
+        if let Some(ws) = focused_workspace(tree) {
+            hist.focus_workspace(ws);
+        }
+
+        hist
+    }
+
+    /// Identities and statistics of the windows in the history, to be saved
+    pub fn saved(&self) -> Vec<SavedWindow> {
+        self.windows
+            .iter()
+            .filter_map(|w| {
+                self.idents.get(w).map(|ident| SavedWindow {
+                    ident: ident.clone(),
+                    stats: self.stats(*w),
+                })
+            })
+            .collect()
+    }
+
+    /// Saves the history, and the transitions if new ones were recorded
+    pub fn save(&mut self) {
+        if let Err(e) = state::save_history(&self.saved()) {
+            eprintln!("warning: could not save focus history: {}", e);
+        }
+        if self.transitions_changed {
+            match state::save_transitions(&self.transitions) {
+                Ok(()) => self.transitions_changed = false,
+                Err(e) => eprintln!("warning: could not save focus transitions: {}", e),
+            }
+        }
+    }
+
+    pub fn ident(&self, wid: i64) -> Option<&WindowIdentity> {
+        self.idents.get(&wid)
+    }
+
+    /// Statistics of a window, including the time it currently holds the
+    /// focus
+    pub fn stats(&self, wid: i64) -> FocusStats {
+        let mut stats = self.stats.get(&wid).cloned().unwrap_or_default();
+        if let Some((fid, since)) = self.focused
+            && fid == wid
+        {
+            stats.focused_ms += since.elapsed().as_millis() as u64;
+            stats.last_focused = utils::now_ms();
+        }
+        stats
+    }
+
+    /// Windows of the history ordered by frecency, highest first
+    ///
+    /// The weight of a window grows with the number of times it got the
+    /// focus and the time it kept it (one point per minute), and decays with
+    /// the time since it was last focused. Ties keep the recency order.
+    pub fn frecency_order(&self) -> Vec<i64> {
+        self.frecency_order_at(utils::now_ms())
+    }
+
+    fn frecency_order_at(&self, now: u64) -> Vec<i64> {
+        let mut scored: Vec<_> = self
+            .windows
+            .iter()
+            .map(|w| (*w, frecency_score(&self.stats(*w), now)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().map(|(w, _)| w).collect()
+    }
+
+    /// Windows of the history ordered by how likely they are to be focused
+    /// after `current`, based on the transitions seen between their
+    /// applications. Ties keep the recency order.
+    pub fn predicted_order(&self, current: Option<i64>) -> Vec<i64> {
+        let app_id = |w: i64| self.ident(w).and_then(|i| i.app_id.as_deref());
+        let from = current.and_then(app_id);
+
+        let mut windows: Vec<i64> = self
+            .windows
+            .iter()
+            .filter(|w| Some(**w) != current)
+            .copied()
+            .collect();
+        if let Some(from) = from {
+            windows.sort_by_key(|w| {
+                let count = app_id(*w).map_or(0, |to| self.transitions.count(from, to));
+                std::cmp::Reverse(count)
+            });
+        }
+        windows
+    }
+
+    /// Account for a change of the focused window, `None` when no window has
+    /// the focus
+    pub fn set_focused(&mut self, wid: Option<i64>) {
+        if self.focused.map(|(fid, _)| fid) == wid {
+            return;
+        }
+
+        if let Some((fid, since)) = self.focused.take() {
+            let stats = self.stats.entry(fid).or_default();
+            stats.focused_ms += since.elapsed().as_millis() as u64;
+            stats.last_focused = utils::now_ms();
+        }
+
+        if let Some(wid) = wid {
+            let stats = self.stats.entry(wid).or_default();
+            stats.focus_count += 1;
+            stats.last_focused = utils::now_ms();
+            self.focused = Some((wid, Instant::now()));
+        }
+    }
+
+    /// Keep track of a new window or of a change of its properties
+    pub fn update(&mut self, node: &swayipc::Node) {
+        self.idents.insert(node.id, WindowIdentity::from_node(node));
+    }
+
+    /// Put a window in front of the history
+    pub fn promote(&mut self, wid: i64) {
+        // dedupe, push front and truncate
+        self.windows.retain(|v| *v != wid);
+        self.windows.push_front(wid);
+        self.windows.truncate(BUFFER_SIZE);
+        self.empty_focus = false;
+    }
+
+    pub fn focus(&mut self, node: &swayipc::Node) {
+        self.update(node);
+        self.record(node.id);
+    }
+
+    /// Like `promote`, also counting the transition from the window focused
+    /// before
+    pub fn record(&mut self, wid: i64) {
+        if !self.empty_focus
+            && let Some(prev) = self.windows.front()
+            && *prev != wid
+            && let (Some(from), Some(to)) = (self.idents.get(prev), self.idents.get(&wid))
+            && let (Some(from), Some(to)) = (&from.app_id, &to.app_id)
+        {
+            self.transitions.record(from, to);
+            self.transitions_changed = true;
+        }
+        self.promote(wid);
+    }
+
+    /// Drop windows which must not appear in the history
+    pub fn exclude(&mut self, excluded: &HashSet<i64>) {
+        if self.windows.front().is_some_and(|w| excluded.contains(w)) {
+            self.empty_focus = true;
+        }
+        self.windows.retain(|w| !excluded.contains(w));
+    }
+
+    pub fn focus_workspace(&mut self, ws: &swayipc::Node) {
+        let Some(name) = &ws.name else {
+            return;
+        };
+
+        self.workspaces.retain(|(_, n)| n != name);
+        self.workspaces.push_front((ws.id, name.clone()));
+        self.workspaces.truncate(BUFFER_SIZE);
+    }
+
+    pub fn rename_workspace(&mut self, ws: &swayipc::Node) {
+        if let Some(name) = &ws.name
+            && let Some(entry) = self.workspaces.iter_mut().find(|(id, _)| *id == ws.id)
+        {
+            entry.1 = name.clone();
+        }
+    }
+
+    /// Names of the workspaces, most recently focused first
+    pub fn workspace_names(&self) -> Vec<String> {
+        self.workspaces.iter().map(|(_, n)| n.clone()).collect()
+    }
+
+    pub fn remove(&mut self, wid: i64) {
+        if self.focused.is_some_and(|(fid, _)| fid == wid) {
+            self.focused = None;
+        }
+        self.windows.retain(|v| *v != wid);
+        self.idents.remove(&wid);
+        self.stats.remove(&wid);
+        self.empty_focus = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static HOUR: u64 = 3600 * 1000;
+
+    fn history(stats: &[(i64, FocusStats)]) -> History {
+        History {
+            windows: stats.iter().map(|(w, _)| *w).collect(),
+            empty_focus: true,
+            workspaces: VecDeque::new(),
+            idents: HashMap::new(),
+            stats: stats.iter().cloned().collect(),
+            focused: None,
+            transitions: Transitions::default(),
+            transitions_changed: false,
+        }
+    }
+
+    fn used(last_focused: u64, focused_ms: u64, focus_count: u32) -> FocusStats {
+        FocusStats {
+            last_focused,
+            focused_ms,
+            focus_count,
+        }
+    }
+
+    #[test]
+    fn frecency_prefers_used_windows() {
+        let now = 100 * HOUR;
+        // glanced at once just before, used often and long an hour ago
+        let hist = history(&[(1, used(now, 1000, 1)), (2, used(now - HOUR, HOUR, 10))]);
+        assert_eq!(hist.frecency_order_at(now), vec![2, 1]);
+
+        // two days later, the recent glance wins over the old habit
+        let hist = history(&[
+            (1, used(now + 48 * HOUR, 1000, 1)),
+            (2, used(now, HOUR, 10)),
+        ]);
+        assert_eq!(hist.frecency_order_at(now + 48 * HOUR), vec![1, 2]);
+    }
+
+    #[test]
+    fn frecency_decays_with_age() {
+        let stats = used(0, HOUR, 10);
+        let fresh = frecency_score(&stats, 0);
+        assert_eq!(fresh, 70.);
+        assert_eq!(frecency_score(&stats, 6 * HOUR), fresh / 2.);
+        assert_eq!(frecency_score(&stats, 12 * HOUR), fresh / 4.);
+    }
+
+    #[test]
+    fn transitions_forget_rare_apps() {
+        let mut t = Transitions::default();
+        for k in 0..TRANSITIONS_PER_APP {
+            t.record("editor", "terminal");
+            t.record("editor", &format!("app{}", k));
+        }
+        assert_eq!(t.0["editor"].len(), TRANSITIONS_PER_APP);
+
+        t.record("editor", "browser");
+        assert_eq!(t.0["editor"].len(), TRANSITIONS_PER_APP);
+        assert_eq!(t.count("editor", "browser"), 1);
+        assert_eq!(t.count("editor", "terminal"), TRANSITIONS_PER_APP as u32);
+    }
+
+    #[test]
+    fn transitions_decay() {
+        let mut t = Transitions::default();
+        t.record("editor", "browser");
+        for _ in 0..TRANSITIONS_MAX_COUNT {
+            t.record("editor", "terminal");
+        }
+        assert_eq!(t.count("editor", "terminal"), TRANSITIONS_MAX_COUNT / 2);
+        assert_eq!(t.count("editor", "browser"), 0);
+    }
+}
@@ -1,32 +1,240 @@
 use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::net::UnixStream;
 
-use std::io::Write;
+use crate::ipc::{
+    Cmd, HistoryEntry, Notification, PROTOCOL_VERSION, Reply, Request, Response, StatsPeriod,
+    SwitchFilter, UsageStats, socket_filename,
+};
 
-use crate::ipc::{Cmd, socket_filename};
+/// Connection to the server, which can carry any number of requests
+///
+/// Requests can be pipelined with `send`, the replies are then read in the
+/// same order with `recv`.
+pub struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+impl Client {
+    pub fn connect() -> Result<Client, Box<dyn Error + Send + Sync>> {
+        let stream = UnixStream::connect(socket_filename()?)?;
+        let reader = BufReader::new(stream.try_clone()?);
+
+        Ok(Client {
+            stream,
+            reader,
+            next_id: 0,
+        })
+    }
+
+    /// Sends a command without waiting for the answer, returns the id of the
+    /// request
+    pub fn send(&mut self, cmd: Cmd) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let req = Request {
+            version: PROTOCOL_VERSION,
+            id: Some(id),
+            cmd,
+        };
+        let mut v = serde_json::to_vec(&req)?;
+        v.push(b'\n');
+        self.stream.write_all(&v)?;
+
+        Ok(id)
+    }
+
+    /// Waits for the next answer of the server, along with the id of its
+    /// request
+    ///
+    /// Errors reported by the server are turned into `ServerError`s
+    pub fn recv(&mut self) -> Result<(Option<u64>, Response), Box<dyn Error + Send + Sync>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(From::from("connection closed by the server"));
+        }
+
+        let value: serde_json::Value = serde_json::from_str(&line)?;
+        if value.as_str() == Some("invalid command") {
+            // servers predating the versioned protocol only answer
+            // "invalid command"
+            return Err(From::from(
+                "the server is older than the client, restart it after upgrading",
+            ));
+        }
+
+        // requests which could not be understood get a bare response
+        let (id, response) = if value.get("response").is_some() {
+            let reply: Reply = serde_json::from_value(value)?;
+            (reply.id, reply.response)
+        } else {
+            (None, serde_json::from_value(value)?)
+        };
+
+        match response {
+            Response::Error(e) => Err(Box::new(e)),
+            r => Ok((id, r)),
+        }
+    }
+
+    /// Sends a command and waits for its answer
+    pub fn request(&mut self, cmd: Cmd) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let id = self.send(cmd)?;
+        let (reply_id, response) = self.recv()?;
+        if reply_id != Some(id) {
+            return Err(From::from("reply to another request"));
+        }
+
+        Ok(response)
+    }
+}
+
+impl Client {
+    /// Get the recently focused window IDs, and whether the focused window is
+    /// not the first of them
+    pub fn focus_history(&mut self) -> Result<(Vec<i64>, bool), Box<dyn Error + Send + Sync>> {
+        match self.request(Cmd::GetHistory)? {
+            Response::History {
+                windows,
+                empty_focus,
+            } => Ok((windows, empty_focus)),
+            r => Err(unexpected(r)),
+        }
+    }
+}
+
+/// Sends a single command on a new connection
+fn request(cmd: Cmd) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    Client::connect()?.request(cmd)
+}
+
+fn unexpected(r: Response) -> Box<dyn Error + Send + Sync> {
+    From::from(format!("unexpected response from the server: {:?}", r))
+}
+
+fn send_cmd(cmd: Cmd) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match request(cmd)? {
+        Response::Ok => Ok(()),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Sends a command focusing a window, returns the id of the window
+fn send_switch(cmd: Cmd) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    match request(cmd)? {
+        Response::Focused(wid) => Ok(wid),
+        r => Err(unexpected(r)),
+    }
+}
 
 /// Focus the last nth window
 ///
-/// Commonly called with `nth_window=1`
-pub fn focus_nth_last_client(nth_window: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut stream = UnixStream::connect(socket_filename()?)?;
+/// Commonly called with `nth_window=1`, returns the id of the focused window
+pub fn focus_nth_last_client(nth_window: usize) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    send_switch(Cmd::SwitchTo(nth_window))
+}
+
+/// Focus the last nth window among the ones allowed by `filter`
+pub fn focus_nth_last_filtered_client(
+    nth_window: usize,
+    filter: SwitchFilter,
+) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    if filter == SwitchFilter::default() {
+        send_switch(Cmd::SwitchTo(nth_window))
+    } else {
+        send_switch(Cmd::SwitchToFiltered(nth_window, filter))
+    }
+}
+
+/// End the current cycle of switches
+///
+/// The window focused by the last switch becomes the most recent one
+pub fn commit_switch_client() -> Result<(), Box<dyn Error + Send + Sync>> {
+    send_cmd(Cmd::Commit)
+}
 
-    serde_json::to_vec(&Cmd::SwitchTo(nth_window))
-        .map(move |b| stream.write_all(b.as_slice()))
-        .ok();
+/// Go back n windows in the focus history
+pub fn back_client(n: usize) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    send_switch(Cmd::Back(n))
+}
+
+/// Go forward n windows, after going back
+pub fn forward_client(n: usize) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    send_switch(Cmd::Forward(n))
+}
 
-    Ok(())
+/// Focus the last nth workspace
+pub fn focus_nth_last_workspace_client(
+    nth_workspace: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    send_cmd(Cmd::SwitchToWorkspace(nth_workspace))
+}
+
+/// Get the recently focused workspace names
+pub fn get_workspace_history() -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    match request(Cmd::GetWorkspaceHistory)? {
+        Response::Workspaces(w) => Ok(w),
+        r => Err(unexpected(r)),
+    }
 }
 
 /// Get the recently focused window IDs
-pub fn get_focus_history() -> Result<(Vec<i64>, bool), Box<dyn Error>> {
-    let mut stream = UnixStream::connect(socket_filename()?)?;
-
-    let out =
-        serde_json::to_vec(&Cmd::GetHistory).map(move |b| -> Result<_, Box<dyn Error>> {
-            stream.write_all(b.as_slice())?;
-            let o = serde_json::from_reader::<_, (Vec<i64>, bool)>(&stream)?;
-            Ok(o)
-        })??;
-    Ok(out)
+pub fn get_focus_history() -> Result<(Vec<i64>, bool), Box<dyn Error + Send + Sync>> {
+    Client::connect()?.focus_history()
+}
+
+/// Get the recently focused windows along with their usage
+pub fn get_history_entries() -> Result<Vec<HistoryEntry>, Box<dyn Error + Send + Sync>> {
+    match request(Cmd::GetHistoryEntries)? {
+        Response::HistoryEntries(e) => Ok(e),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Get the window IDs ranked by frecency
+pub fn get_frecency_history() -> Result<Vec<i64>, Box<dyn Error + Send + Sync>> {
+    match request(Cmd::GetFrecencyHistory)? {
+        Response::Windows(w) => Ok(w),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Get the window IDs ranked by how likely they are to follow the focused one
+pub fn get_predicted_history() -> Result<Vec<i64>, Box<dyn Error + Send + Sync>> {
+    match request(Cmd::GetPredictedHistory)? {
+        Response::Windows(w) => Ok(w),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Get the focused time per application and workspace over `period`
+pub fn get_stats(period: StatsPeriod) -> Result<UsageStats, Box<dyn Error + Send + Sync>> {
+    match request(Cmd::GetStats(period))? {
+        Response::Stats(s) => Ok(s),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Subscribe to the changes of the history, `f` is called with each of them
+/// until the server goes away or `f` fails
+pub fn watch_history<F>(mut f: F) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnMut(Notification) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let mut client = Client::connect()?;
+
+    match client.request(Cmd::Subscribe)? {
+        Response::Ok => (),
+        r => return Err(unexpected(r)),
+    }
+
+    loop {
+        match client.recv()? {
+            (_, Response::Event(n)) => f(n)?,
+            (_, r) => return Err(unexpected(r)),
+        }
+    }
 }
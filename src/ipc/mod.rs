@@ -1,16 +1,262 @@
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
 pub mod client;
 pub mod server;
 
+/// Version of the client-server protocol, to be increased on incompatible
+/// changes of `Cmd` or `Response`
+pub static PROTOCOL_VERSION: u32 = 5;
+
 pub fn socket_filename() -> Result<String, Box<env::VarError>> {
     Ok(env::var("HOME")? + "/.local/share/i3-focus-last.sock")
 }
 
+/// Set of windows considered when switching
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scope {
+    #[default]
+    Global,
+    /// Windows of the focused workspace
+    Workspace,
+    /// Windows on the workspaces of the focused output
+    Output,
+    /// Sibling windows of the focused one, in the same tabbed or stacked
+    /// container
+    Container,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(Scope::Global),
+            "workspace" => Ok(Scope::Workspace),
+            "output" => Ok(Scope::Output),
+            "container" => Ok(Scope::Container),
+            _ => Err(format!("unknown scope `{}`", s)),
+        }
+    }
+}
+
+/// Restrictions on the windows considered when switching
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct SwitchFilter {
+    pub scope: Scope,
+    /// Only windows of the same application (app_id or X11 class) as the
+    /// focused one
+    pub same_app: bool,
+    /// Order the windows by how likely they are to follow the focused one
+    pub predicted: bool,
+    /// Only urgent windows, most recently urgent first
+    pub urgent: bool,
+    /// Leave out the windows hidden in the scratchpad
+    pub no_scratchpad: bool,
+    /// Leave out the windows already on screen
+    pub skip_visible: bool,
+    /// Only windows outside of the focused workspace
+    pub other_workspace: bool,
+    /// Only floating (`Some(true)`) or tiled (`Some(false)`) windows
+    pub floating: Option<bool>,
+}
+
+/// How a window has been used
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FocusStats {
+    /// Last time the window had the focus, in ms since the epoch
+    pub last_focused: u64,
+    /// Total time the window had the focus, in ms
+    pub focused_ms: u64,
+    /// Number of times the window got the focus
+    pub focus_count: u32,
+}
+
+/// A window of the history with its usage
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: i64,
+    #[serde(flatten)]
+    pub stats: FocusStats,
+}
+
+/// Time over which usage statistics are computed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatsPeriod {
+    /// Over the last 24 hours
+    #[default]
+    LastDay,
+    LastHour,
+    /// Since the server started
+    SinceStart,
+}
+
+impl FromStr for StatsPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(StatsPeriod::LastDay),
+            "hour" => Ok(StatsPeriod::LastHour),
+            "start" => Ok(StatsPeriod::SinceStart),
+            _ => Err(format!("unknown period `{}`", s)),
+        }
+    }
+}
+
+/// Usage of an application or a workspace
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageEntry {
+    /// app_id, X11 class or workspace name
+    pub name: String,
+    /// Time it had the focus, in ms
+    pub focused_ms: u64,
+    /// Number of times it got the focus
+    pub switches: u32,
+}
+
+/// Usage statistics, most used first
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageStats {
+    pub apps: Vec<UsageEntry>,
+    pub workspaces: Vec<UsageEntry>,
+}
+
 /// Commands sent for client-server interfacing
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Cmd {
     SwitchTo(usize),
+    SwitchToFiltered(usize, SwitchFilter),
+    /// End a cycle of switches
+    Commit,
+    /// Navigate the history like in a browser
+    Back(usize),
+    Forward(usize),
     GetHistory,
+    /// Like `GetHistory`, with the usage of each window
+    GetHistoryEntries,
+    /// Window IDs ranked by frecency
+    GetFrecencyHistory,
+    /// Window IDs ranked by how likely they are to follow the focused one
+    GetPredictedHistory,
+    SwitchToWorkspace(usize),
+    /// List workspace names by recency
+    GetWorkspaceHistory,
+    /// Focused time per application and workspace
+    GetStats(StatsPeriod),
+    /// Stream the changes of the history, the connection then carries
+    /// `Response::Event`s only
+    Subscribe,
+}
+
+/// Change of the history sent to subscribers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HistoryEvent {
+    /// A window became the most recent one
+    Focused(i64),
+    /// A window left the history
+    Removed(i64),
+    /// The history changed in another way, new order of the windows
+    Reordered(Vec<i64>),
+    /// A workspace got the focus
+    WorkspaceFocused(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification {
+    /// Increases by one with each event sent by the server
+    pub seq: u64,
+    pub event: HistoryEvent,
+}
+
+/// Envelope of the commands sent to the server
+///
+/// Requests and replies are sent as newline-delimited JSON. A connection can
+/// carry any number of requests, which are answered in order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub version: u32,
+    /// Chosen by the client, repeated in the reply
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub cmd: Cmd,
+}
+
+/// Answer to a `Request`
+///
+/// Requests which can't be understood (wrong version, invalid JSON) are
+/// answered with a bare `Response::Error` instead, after which the server
+/// closes the connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reply {
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub response: Response,
+}
+
+/// Kind of failure reported by the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request could not be parsed
+    InvalidRequest,
+    /// Client and server use different protocol versions
+    VersionMismatch,
+    /// There is no window or workspace to switch to
+    Unavailable,
+    /// The command could not be carried out
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ServerError {
+        ServerError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ServerError {}
+
+impl From<swayipc::Error> for ServerError {
+    fn from(e: swayipc::Error) -> ServerError {
+        ServerError::new(ErrorCode::Failed, e.to_string())
+    }
+}
+
+/// Answer of the server to a `Request`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The command was carried out
+    Ok,
+    /// The window focused by a switch
+    Focused(i64),
+    History {
+        windows: Vec<i64>,
+        /// Whether the focused window is not the first of `windows`
+        empty_focus: bool,
+    },
+    HistoryEntries(Vec<HistoryEntry>),
+    /// Window IDs, in the order asked for
+    Windows(Vec<i64>),
+    Workspaces(Vec<String>),
+    Stats(UsageStats),
+    Event(Notification),
+    Error(ServerError),
 }
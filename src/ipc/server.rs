@@ -1,63 +1,229 @@
-use std::collections::VecDeque;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
-use std::io::Write;
+use std::io::{BufReader, Write};
 use std::net::Shutdown;
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use signal_hook::consts::*;
 use signal_hook::iterator::Signals;
 
-use serde::de::Deserialize;
-
 use gumdrop::Options;
 
-use crate::ipc::{Cmd, socket_filename};
+use crate::history::History;
+use crate::ipc::{
+    Cmd, ErrorCode, HistoryEntry, HistoryEvent, Notification, PROTOCOL_VERSION, Reply, Request,
+    Response, Scope, ServerError, SwitchFilter, socket_filename,
+};
+use crate::layout::Layout;
+use crate::rules::{self, ExcludeRules};
+use crate::state;
+use crate::usage::Usage;
 use crate::utils;
 
-static BUFFER_SIZE: usize = 100;
+static RECONNECT_ATTEMPTS: u32 = 10;
+static RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+/// Delay during which changes of the history are gathered before saving it
+static SAVE_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Options)]
-pub struct ServerOpts {}
+pub struct ServerOpts {
+    #[options(
+        help = "time in ms a window must keep focus before entering the history",
+        default = "0"
+    )]
+    dwell: u64,
+
+    #[options(
+        help = "path to exclusion rules",
+        default = "~/.config/i3-focus-last/exclude.json"
+    )]
+    exclude_rules: String,
+
+    #[options(
+        help = "time in ms during which switches walk deeper in the history (0 disables)",
+        default = "0"
+    )]
+    cycle_timeout: u64,
+
+    #[options(
+        no_short,
+        help = "walk deeper in the history on consecutive switches until `switch --commit`"
+    )]
+    cycle: bool,
+}
+
+/// Try to focus a window, returns false if it could not be focused
+fn focus_window(
+    conn: &mut swayipc::Connection,
+    layout: &Layout,
+    wid: i64,
+) -> Result<bool, swayipc::Error> {
+    let r = conn.run_command(utils::focus_command(wid, layout.in_scratchpad(wid)))?;
+
+    Ok(r.first().is_some_and(|o| o.is_ok()))
+}
 
+/// Focus the nth window of `windows`, starting from 1
 fn focus_nth<'a, I>(
     conn: &mut swayipc::Connection,
+    layout: &Layout,
     windows: I,
     n: usize,
-) -> Result<(), Box<dyn Error>>
+) -> Result<i64, ServerError>
 where
     I: IntoIterator<Item = &'a i64>,
 {
     // Start from the nth window and try to change focus until it succeeds
     // (so that it skips windows which no longer exist)
     for (k, wid) in windows.into_iter().enumerate() {
-        if k < n {
+        if k + 1 < n {
             continue;
         }
 
-        let r = conn.run_command(format!("[con_id={}] focus", wid).as_str())?;
-
-        if let Some(o) = r.first()
-            && o.is_ok()
-        {
-            return Ok(());
+        if focus_window(conn, layout, *wid)? {
+            return Ok(*wid);
         }
     }
 
-    Err(From::from(format!("Last window {} unavailable", n)))
+    Err(ServerError::new(
+        ErrorCode::Unavailable,
+        format!("Last window {} unavailable", n),
+    ))
 }
 
 #[derive(Debug)]
 enum ServerEvent {
     I3Event(swayipc::Event),
-    SwitchTo(usize),
-    GetHistory(mpsc::Sender<(Vec<i64>, bool)>),
+    WmReconnected,
+    Request(Cmd, mpsc::Sender<Response>),
+    Subscribe(mpsc::Sender<Response>),
     Stop(Result<(), Box<dyn Error + Send + Sync>>),
 }
 
+/// Checks that a request uses the same protocol version as the server
+fn parse_request(value: serde_json::Value) -> Result<Request, ServerError> {
+    let invalid = |e: serde_json::Error| ServerError::new(ErrorCode::InvalidRequest, e.to_string());
+    let mismatch = |version| {
+        ServerError::new(
+            ErrorCode::VersionMismatch,
+            format!(
+                "client protocol version {} differs from the server's ({}), \
+                 restart the server after upgrading",
+                version, PROTOCOL_VERSION
+            ),
+        )
+    };
+
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(version) if version == PROTOCOL_VERSION as u64 => {
+            serde_json::from_value(value).map_err(invalid)
+        }
+        Some(version) => Err(mismatch(version)),
+        // clients predating the versioned protocol send bare commands
+        None if serde_json::from_value::<Cmd>(value.clone()).is_ok() => Err(mismatch(0)),
+        None => Err(ServerError::new(
+            ErrorCode::InvalidRequest,
+            "missing protocol version",
+        )),
+    }
+}
+
+/// Sends a value as one line of JSON
+fn write_line<T: serde::Serialize>(
+    mut stream: &UnixStream,
+    value: &T,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut v = serde_json::to_vec(value)?;
+    v.push(b'\n');
+    stream.write_all(&v)?;
+    Ok(())
+}
+
+/// Answers the requests of a client until it closes the connection
+fn serve_client(
+    stream: &UnixStream,
+    event_chan: &mpsc::Sender<ServerEvent>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // requests are read as a stream of JSON values rather than by line:
+    // clients predating the versioned protocol send no newline and wait for
+    // the answer
+    let values = serde_json::Deserializer::from_reader(BufReader::new(stream))
+        .into_iter::<serde_json::Value>();
+
+    for value in values {
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                // there is no telling what the client will send next
+                let e = ServerError::new(ErrorCode::InvalidRequest, e.to_string());
+                write_line(stream, &Response::Error(e))?;
+                break;
+            }
+        };
+        let id = value.get("id").and_then(|v| v.as_u64());
+
+        let req = match parse_request(value) {
+            Ok(req) => req,
+            Err(e) if e.code == ErrorCode::VersionMismatch => {
+                // replies of another version would not be understood either
+                write_line(stream, &Response::Error(e))?;
+                break;
+            }
+            Err(e) => {
+                // unknown commands of newer clients, the next ones may be fine
+                write_line(
+                    stream,
+                    &Reply {
+                        id,
+                        response: Response::Error(e),
+                    },
+                )?;
+                continue;
+            }
+        };
+
+        if let Cmd::Subscribe = req.cmd {
+            return stream_events(stream, event_chan, req.id);
+        }
+
+        let (resp_tx, resp_rx) = mpsc::channel::<Response>();
+        event_chan.send(ServerEvent::Request(req.cmd, resp_tx))?;
+        write_line(
+            stream,
+            &Reply {
+                id: req.id,
+                response: resp_rx.recv()?,
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sends the changes of the history until the client goes away
+fn stream_events(
+    stream: &UnixStream,
+    event_chan: &mpsc::Sender<ServerEvent>,
+    id: Option<u64>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (events_tx, events_rx) = mpsc::channel::<Response>();
+    event_chan.send(ServerEvent::Subscribe(events_tx))?;
+
+    for response in std::iter::once(Response::Ok).chain(events_rx) {
+        if write_line(stream, &Reply { id, response }).is_err() {
+            // unsubscribed by closing the connection
+            break;
+        }
+    }
+
+    Ok(())
+}
+
 fn cmd_listener(event_chan: mpsc::Sender<ServerEvent>) -> Result<(), Box<dyn Error + Send + Sync>> {
     let socket = socket_filename()?;
     let socket = Path::new(&socket);
@@ -69,30 +235,10 @@ fn cmd_listener(event_chan: mpsc::Sender<ServerEvent>) -> Result<(), Box<dyn Err
     let listener = UnixListener::bind(socket)?;
 
     for stream in listener.incoming() {
-        let mut stream = stream?;
+        let stream = stream?;
         let event_chan = event_chan.clone();
         thread::spawn(move || {
-            let mut de = serde_json::Deserializer::from_reader(&stream);
-            let cmd = Cmd::deserialize(&mut de);
-
-            let res = (|| -> Result<(), Box<dyn Error + Send + Sync>> {
-                match cmd {
-                    Ok(Cmd::SwitchTo(n)) => {
-                        event_chan.send(ServerEvent::SwitchTo(n))?;
-                    }
-                    Ok(Cmd::GetHistory) => {
-                        let (hist_tx, hist_rx) = mpsc::channel::<(Vec<i64>, bool)>();
-                        event_chan.send(ServerEvent::GetHistory(hist_tx))?;
-                        let res = hist_rx.recv().unwrap();
-                        let v = serde_json::to_vec::<(Vec<_>, bool)>(&res).unwrap();
-                        let _ = &stream.write(&v);
-                    }
-                    _ => {
-                        let _ = serde_json::to_writer(&stream, "invalid command");
-                    }
-                }
-                Ok(())
-            })();
+            let res = serve_client(&stream, &event_chan);
 
             let _ = stream.shutdown(Shutdown::Both);
 
@@ -105,23 +251,55 @@ fn cmd_listener(event_chan: mpsc::Sender<ServerEvent>) -> Result<(), Box<dyn Err
     Ok(())
 }
 
+fn subscribe() -> Result<swayipc::EventStream, swayipc::Error> {
+    swayipc::Connection::new()?.subscribe([
+        swayipc::EventType::Workspace,
+        swayipc::EventType::Window,
+        swayipc::EventType::Output,
+        swayipc::EventType::Shutdown,
+    ])
+}
+
+/// Try to subscribe again after the window manager went away, with an
+/// exponential backoff
+fn resubscribe() -> Result<swayipc::EventStream, Box<dyn Error + Send + Sync>> {
+    let mut delay = Duration::from_millis(100);
+
+    for _ in 0..RECONNECT_ATTEMPTS {
+        thread::sleep(delay);
+        if let Ok(events) = subscribe() {
+            return Ok(events);
+        }
+        delay = std::cmp::min(delay * 2, RECONNECT_MAX_DELAY);
+    }
+
+    Err(From::from("could not reconnect to the window manager"))
+}
+
 /// Run the focus server that answers clients using the IPC
 fn i3events_listener(
     event_chan: mpsc::Sender<ServerEvent>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let conn = swayipc::Connection::new()?;
     // Listens to i3 event
-    let events = conn.subscribe([swayipc::EventType::Workspace, swayipc::EventType::Window])?;
+    let mut events = subscribe()?;
 
-    for event in events {
-        let server_ev = match event {
-            Ok(ev) => ServerEvent::I3Event(ev),
-            Err(err) => ServerEvent::Stop(Err(Box::new(err))),
-        };
-        event_chan.send(server_ev)?;
-    }
+    loop {
+        for event in events {
+            match event {
+                // i3 announces restarts with a shutdown event (that swayipc
+                // may not be able to parse) before closing the connection
+                Ok(swayipc::Event::Shutdown(_)) => break,
+                Ok(ev) => event_chan.send(ServerEvent::I3Event(ev))?,
+                Err(err) => {
+                    eprintln!("warning: lost connection to the window manager: {}", err);
+                    break;
+                }
+            }
+        }
 
-    Ok(())
+        events = resubscribe()?;
+        event_chan.send(ServerEvent::WmReconnected)?;
+    }
 }
 
 fn interrupt_listener(
@@ -150,7 +328,644 @@ where
     });
 }
 
-pub fn focus_server() -> Result<(), Box<dyn Error + Send + Sync>> {
+/// Consecutive switches walking down a frozen history
+struct Cycle {
+    filter: SwitchFilter,
+    windows: Vec<i64>,
+    /// Position of the last target in `windows`, -1 when the window focused
+    /// at the start of the cycle was not part of it
+    pos: isize,
+    target: Option<i64>,
+    last: Instant,
+    /// Urgent windows when the cycle started
+    urgent: Vec<i64>,
+}
+
+struct Server {
+    conn: swayipc::Connection,
+    hist: History,
+    layout: Layout,
+    rules: ExcludeRules,
+    excluded: HashSet<i64>,
+    dwell: Duration,
+    /// Window which got focus but has not yet kept it for the dwell time
+    pending: Option<(swayipc::Node, Instant)>,
+    cycle_timeout: Duration,
+    /// Whether consecutive switches walk deeper in the history
+    cycling: bool,
+    cycle: Option<Cycle>,
+    /// Windows left by going back, the next one to go forward to last
+    forward: Vec<i64>,
+    /// Window being focused by a back or forward navigation
+    nav_target: Option<i64>,
+    usage: Usage,
+    /// Windows demanding attention, most recently urgent first
+    urgent: Vec<i64>,
+    subscribers: Vec<mpsc::Sender<Response>>,
+    /// Sequence number of the last event sent to subscribers
+    seq: u64,
+    /// State of the history when subscribers were last notified
+    notified: Snapshot,
+    /// When the history has to be saved, if it changed
+    save_at: Option<Instant>,
+}
+
+/// What subscribers are told about
+#[derive(Default)]
+struct Snapshot {
+    windows: Vec<i64>,
+    workspace: Option<String>,
+}
+
+impl Snapshot {
+    /// Events leading from `old` to this snapshot
+    fn changes(&self, old: &Snapshot) -> Vec<HistoryEvent> {
+        let mut events = vec![];
+
+        if self.workspace != old.workspace
+            && let Some(ws) = &self.workspace
+        {
+            events.push(HistoryEvent::WorkspaceFocused(ws.clone()));
+        }
+
+        for w in &old.windows {
+            if !self.windows.contains(w) {
+                events.push(HistoryEvent::Removed(*w));
+            }
+        }
+
+        // order expected from the removals and the focus alone
+        let mut expected: Vec<i64> = old
+            .windows
+            .iter()
+            .filter(|w| self.windows.contains(w))
+            .copied()
+            .collect();
+
+        if let Some(front) = self.windows.first()
+            && expected.first() != Some(front)
+        {
+            events.push(HistoryEvent::Focused(*front));
+            expected.retain(|w| w != front);
+            expected.insert(0, *front);
+        }
+
+        if expected != self.windows {
+            events.push(HistoryEvent::Reordered(self.windows.clone()));
+        }
+
+        events
+    }
+}
+
+impl Server {
+    fn new(opts: ServerOpts) -> Result<Server, Box<dyn Error + Send + Sync>> {
+        let mut conn = swayipc::Connection::new()?;
+
+        // restore the history of a previous run, keeping only windows which
+        // still exist
+        let saved = state::load_history().unwrap_or_else(|e| {
+            eprintln!("warning: could not load saved focus history: {}", e);
+            vec![]
+        });
+        let rules = rules::read_exclude_rules(Some(&opts.exclude_rules));
+        let tree = conn.get_tree()?;
+        let layout = Layout::from_tree(&tree);
+        let excluded = rules.excluded_windows(&tree, &layout);
+        let mut hist = History::from_tree(&tree, &saved);
+        hist.exclude(&excluded);
+        hist.transitions = state::load_transitions().unwrap_or_else(|e| {
+            eprintln!("warning: could not load saved focus transitions: {}", e);
+            Default::default()
+        });
+
+        Ok(Server {
+            conn,
+            hist,
+            layout,
+            rules,
+            excluded,
+            dwell: Duration::from_millis(opts.dwell),
+            pending: None,
+            cycle_timeout: Duration::from_millis(opts.cycle_timeout),
+            cycling: opts.cycle || opts.cycle_timeout > 0,
+            cycle: None,
+            forward: vec![],
+            nav_target: None,
+            usage: Usage::new(),
+            urgent: vec![],
+            subscribers: vec![],
+            seq: 0,
+            notified: Snapshot::default(),
+            save_at: None,
+        })
+    }
+
+    /// Whether the focused window is the first of the history
+    fn focus_in_front(&self) -> bool {
+        !self.hist.empty_focus && self.pending.is_none() && self.cycle.is_none()
+    }
+
+    /// The currently focused window, if known
+    fn focused(&self) -> Option<i64> {
+        if let Some((node, _)) = &self.pending {
+            Some(node.id)
+        } else if let Some(cycle) = &self.cycle {
+            cycle.target
+        } else if !self.hist.empty_focus {
+            self.hist.windows.front().copied()
+        } else {
+            None
+        }
+    }
+
+    /// Next time a pending window, a cycle or a save should be handled
+    fn deadline(&self) -> Option<Instant> {
+        let pending = self.pending.as_ref().map(|(_, since)| *since + self.dwell);
+        let cycle = self
+            .cycle
+            .as_ref()
+            .filter(|_| !self.cycle_timeout.is_zero())
+            .map(|c| c.last + self.cycle_timeout);
+        pending.into_iter().chain(cycle).chain(self.save_at).min()
+    }
+
+    /// Save the history once the changes of the next few seconds are in
+    fn save_later(&mut self) {
+        self.save_at
+            .get_or_insert_with(|| Instant::now() + SAVE_DELAY);
+    }
+
+    /// Save the history now if it changed
+    fn flush(&mut self) {
+        if self.save_at.take().is_some() {
+            self.hist.save();
+        }
+    }
+
+    fn expire(&mut self) {
+        if let Some((node, since)) = &self.pending
+            && since.elapsed() >= self.dwell
+        {
+            self.hist.focus(node);
+            self.save_later();
+            self.pending = None;
+        }
+
+        if !self.cycle_timeout.is_zero()
+            && self
+                .cycle
+                .as_ref()
+                .is_some_and(|c| c.last.elapsed() >= self.cycle_timeout)
+        {
+            self.commit();
+        }
+
+        if self.save_at.is_some_and(|t| t <= Instant::now()) {
+            self.flush();
+        }
+    }
+
+    /// End the current cycle, its last target becomes the most recent window
+    fn commit(&mut self) {
+        if let Some(cycle) = self.cycle.take()
+            && let Some(target) = cycle.target
+        {
+            self.hist.record(target);
+            self.save_later();
+        }
+    }
+
+    fn window_focused(&mut self, node: swayipc::Node) {
+        self.hist.set_focused(Some(node.id));
+        if let Some(app) = utils::node_display_id(&node) {
+            let workspace = self.layout.get(node.id).and_then(|i| i.workspace.clone());
+            self.usage.focus(Some((app, workspace)));
+        } else {
+            self.usage.focus(None);
+        }
+
+        if let Some(cycle) = &self.cycle {
+            if cycle.target == Some(node.id) {
+                // focus moved by the cycle itself, the history stays frozen
+                self.hist.update(&node);
+                return;
+            }
+            self.commit();
+        }
+
+        self.pending = None;
+        if self.nav_target.take() == Some(node.id) {
+            // reached by going back or forward, record it without delay
+            self.hist.focus(&node);
+            self.save_later();
+            return;
+        }
+
+        // a new manual focus truncates the forward navigation
+        self.forward.clear();
+
+        if self.excluded.contains(&node.id) {
+            self.hist.empty_focus = true;
+        } else if self.dwell.is_zero() || self.hist.windows.front() == Some(&node.id) {
+            self.hist.focus(&node);
+            self.save_later();
+        } else {
+            self.hist.update(&node);
+            self.pending = Some((node, Instant::now()));
+        }
+    }
+
+    /// Update the layout and the excluded windows from the current tree
+    fn refresh(&mut self) {
+        match self.conn.get_tree() {
+            Ok(tree) => {
+                self.layout = Layout::from_tree(&tree);
+                self.excluded = self.rules.excluded_windows(&tree, &self.layout);
+                self.hist.exclude(&self.excluded);
+            }
+            Err(e) => eprintln!("warning: could not get tree: {}", e),
+        }
+    }
+
+    /// Apply the exclusion rules to a window, with its cached layout
+    fn check_rules(&mut self, node: &swayipc::Node) {
+        if !self.rules.excluded(node, self.layout.get(node.id)) {
+            self.excluded.remove(&node.id);
+        } else if self.excluded.insert(node.id) {
+            self.hist.exclude(&self.excluded);
+        }
+    }
+
+    fn handle_i3_event(&mut self, e: swayipc::Event) {
+        // keep track of windows moving between workspaces and outputs;
+        // the properties of a focused or renamed window may also have
+        // changed, in which case the exclusion rules have to be checked again
+        match &e {
+            swayipc::Event::Window(e) => match e.change {
+                swayipc::WindowChange::New
+                | swayipc::WindowChange::Move
+                | swayipc::WindowChange::Floating => self.refresh(),
+                swayipc::WindowChange::Focus | swayipc::WindowChange::Title
+                    if !self.rules.is_empty() =>
+                {
+                    self.check_rules(&e.container)
+                }
+                _ => {}
+            },
+            _ => self.refresh(),
+        }
+
+        if let swayipc::Event::Window(e) = e {
+            match e.change {
+                swayipc::WindowChange::Focus => self.window_focused(e.container),
+                swayipc::WindowChange::Close => {
+                    if self.focused() == Some(e.container.id) {
+                        self.usage.focus(None);
+                    }
+                    self.commit();
+                    if self
+                        .pending
+                        .as_ref()
+                        .is_some_and(|(n, _)| n.id == e.container.id)
+                    {
+                        self.pending = None;
+                    }
+                    self.forward.retain(|w| *w != e.container.id);
+                    self.urgent.retain(|w| *w != e.container.id);
+                    self.hist.remove(e.container.id);
+                    self.save_later();
+                }
+                swayipc::WindowChange::New | swayipc::WindowChange::Title => {
+                    self.hist.update(&e.container);
+                }
+                swayipc::WindowChange::Urgent => {
+                    self.urgent.retain(|w| *w != e.container.id);
+                    if e.container.urgent {
+                        self.urgent.insert(0, e.container.id);
+                    }
+                }
+                _ => {}
+            }
+        } else if let swayipc::Event::Workspace(e) = e {
+            match (e.change, &e.current) {
+                (swayipc::WorkspaceChange::Focus, Some(ws)) => {
+                    self.hist.empty_focus = true;
+                    self.hist.set_focused(None);
+                    self.usage.focus(None);
+                    self.hist.focus_workspace(ws);
+                }
+                (swayipc::WorkspaceChange::Rename, Some(ws)) => {
+                    self.hist.rename_workspace(ws);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // con_ids are not preserved across i3 restarts, remap the history to
+        // the new tree
+        self.conn = swayipc::Connection::new()?;
+        let workspaces = std::mem::take(&mut self.hist.workspaces);
+        let transitions = std::mem::take(&mut self.hist.transitions);
+        self.hist = History::from_tree(&self.conn.get_tree()?, &self.hist.saved());
+        self.hist.transitions = transitions;
+        // keep the older workspaces behind the focused one
+        for ws in workspaces {
+            if !self.hist.workspaces.iter().any(|(_, n)| *n == ws.1) {
+                self.hist.workspaces.push_back(ws);
+            }
+        }
+        self.refresh();
+        self.save_later();
+        self.pending = None;
+        self.cycle = None;
+        self.forward.clear();
+        self.nav_target = None;
+        self.urgent.clear();
+
+        Ok(())
+    }
+
+    /// Windows of the history allowed by `filter`, most recent or most likely
+    /// to be the next one first
+    fn filtered_windows(&self, filter: &SwitchFilter) -> Vec<i64> {
+        let app_id = |w: i64| self.hist.ident(w).and_then(|i| i.app_id.as_ref());
+        let current_app = self.focused().and_then(app_id);
+
+        let windows = if filter.urgent {
+            let mut urgent = self.urgent.clone();
+            urgent.retain(|w| !self.excluded.contains(w));
+            urgent
+        } else if filter.predicted {
+            self.hist.predicted_order(self.focused())
+        } else {
+            Vec::from_iter(self.hist.windows.iter().copied())
+        };
+
+        windows
+            .iter()
+            .filter(|w| self.layout.in_scope(**w, filter.scope))
+            .filter(|w| !filter.same_app || (current_app.is_some() && app_id(**w) == current_app))
+            .filter(|w| !filter.no_scratchpad || !self.layout.in_scratchpad(**w))
+            .filter(|w| !filter.skip_visible || !self.layout.is_visible(**w))
+            .filter(|w| !filter.other_workspace || !self.layout.in_scope(**w, Scope::Workspace))
+            .filter(|w| {
+                filter
+                    .floating
+                    .is_none_or(|f| self.layout.get(**w).is_some_and(|i| i.floating == f))
+            })
+            .copied()
+            .collect()
+    }
+
+    fn switch_to(&mut self, n: usize, filter: SwitchFilter) -> Result<i64, ServerError> {
+        self.forward.clear();
+        self.nav_target = None;
+
+        // visibility and container layouts also change without any
+        // structural event
+        if (filter.skip_visible || filter.scope == Scope::Container) && self.cycle.is_none() {
+            self.refresh();
+        }
+
+        // switching within an application or between urgent windows always
+        // cycles, so that repeated presses go through all of them
+        if !self.cycling && !filter.same_app && !filter.urgent {
+            self.commit();
+            let current = self.focused();
+            let mut windows = self.filtered_windows(&filter);
+            windows.retain(|w| Some(*w) != current);
+            return focus_nth(&mut self.conn, &self.layout, &windows, n);
+        }
+
+        // windows which became urgent since the start of the cycle come first
+        if self.cycle.as_ref().is_some_and(|c| {
+            c.filter != filter
+                || (filter.urgent && self.urgent.iter().any(|w| !c.urgent.contains(w)))
+        }) {
+            self.commit();
+        }
+
+        let mut cycle = match self.cycle.take() {
+            Some(cycle) => cycle,
+            None => {
+                let windows = self.filtered_windows(&filter);
+                let current = self.focused();
+                let pos = if current.is_some() && windows.first() == current.as_ref() {
+                    0
+                } else {
+                    -1
+                };
+                Cycle {
+                    filter,
+                    windows,
+                    pos,
+                    target: None,
+                    last: Instant::now(),
+                    urgent: self.urgent.clone(),
+                }
+            }
+        };
+
+        // walk deeper in the frozen history, wrapping around at the end
+        let len = cycle.windows.len() as isize;
+        let mut res = Err(ServerError::new(
+            ErrorCode::Unavailable,
+            format!("Last window {} unavailable", n),
+        ));
+        for k in 0..len {
+            let pos = (cycle.pos + n as isize + k).rem_euclid(len);
+            let wid = cycle.windows[pos as usize];
+            if focus_window(&mut self.conn, &self.layout, wid)? {
+                cycle.pos = pos;
+                cycle.target = Some(wid);
+                res = Ok(wid);
+                break;
+            }
+        }
+
+        // a cycle which never focused anything would hide the focused window
+        if cycle.target.is_some() {
+            self.pending = None;
+            cycle.last = Instant::now();
+            self.cycle = Some(cycle);
+        }
+
+        res
+    }
+
+    /// Go back n windows in the history, like in a browser
+    ///
+    /// The windows left behind can be reached again with `forward`
+    fn back(&mut self, n: usize) -> Result<i64, ServerError> {
+        self.commit();
+
+        let current = self.focused();
+        let candidates: Vec<i64> = self
+            .hist
+            .windows
+            .iter()
+            .filter(|w| Some(**w) != current && !self.forward.contains(w))
+            .copied()
+            .collect();
+
+        for (k, wid) in candidates.iter().enumerate().skip(n.saturating_sub(1)) {
+            if focus_window(&mut self.conn, &self.layout, *wid)? {
+                self.forward.extend(current);
+                self.forward.extend(&candidates[..k]);
+                self.nav_target = Some(*wid);
+                return Ok(*wid);
+            }
+        }
+
+        Err(ServerError::new(
+            ErrorCode::Unavailable,
+            "No window to go back to",
+        ))
+    }
+
+    /// Go forward n windows after going back
+    fn forward(&mut self, n: usize) -> Result<i64, ServerError> {
+        self.commit();
+
+        let mut skipped = vec![];
+        while let Some(wid) = self.forward.pop() {
+            if skipped.len() + 1 < n {
+                skipped.push(wid);
+                continue;
+            }
+
+            if focus_window(&mut self.conn, &self.layout, wid)? {
+                // the windows passed over come before the target when going
+                // back again
+                for w in skipped {
+                    self.hist.promote(w);
+                }
+                self.nav_target = Some(wid);
+                return Ok(wid);
+            }
+        }
+
+        Err(ServerError::new(
+            ErrorCode::Unavailable,
+            "No window to go forward to",
+        ))
+    }
+
+    /// Focus the nth most recent workspace, the focused one being the 0th
+    fn switch_to_workspace(&mut self, n: usize) -> Result<(), ServerError> {
+        let Some((_, name)) = self.hist.workspaces.get(n) else {
+            return Err(ServerError::new(
+                ErrorCode::Unavailable,
+                format!("Last workspace {} unavailable", n),
+            ));
+        };
+
+        let cmd = format!(
+            "workspace --no-auto-back-and-forth \"{}\"",
+            name.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        for r in self.conn.run_command(cmd)? {
+            r?;
+        }
+
+        Ok(())
+    }
+
+    fn history_entries(&self) -> Vec<HistoryEntry> {
+        self.hist
+            .windows
+            .iter()
+            .map(|w| HistoryEntry {
+                id: *w,
+                stats: self.hist.stats(*w),
+            })
+            .collect()
+    }
+
+    fn history(&self) -> (Vec<i64>, bool) {
+        let windows = Vec::from_iter(self.hist.windows.iter().cloned());
+        (windows, !self.focus_in_front())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            windows: Vec::from_iter(self.hist.windows.iter().copied()),
+            workspace: self.hist.workspaces.front().map(|(_, n)| n.clone()),
+        }
+    }
+
+    fn subscribe(&mut self, chan: mpsc::Sender<Response>) {
+        if self.subscribers.is_empty() {
+            self.notified = self.snapshot();
+        }
+        self.subscribers.push(chan);
+    }
+
+    /// Sends the changes of the history since the last call to subscribers
+    fn notify(&mut self) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let snapshot = self.snapshot();
+        let events = snapshot.changes(&self.notified);
+        self.notified = snapshot;
+
+        for event in events {
+            self.seq += 1;
+            let notification = Notification {
+                seq: self.seq,
+                event,
+            };
+            self.subscribers
+                .retain(|s| s.send(Response::Event(notification.clone())).is_ok());
+        }
+    }
+
+    fn handle_cmd(&mut self, cmd: Cmd) -> Response {
+        let res = match cmd {
+            Cmd::SwitchTo(n) => self
+                .switch_to(n, SwitchFilter::default())
+                .map(Response::Focused),
+            Cmd::SwitchToFiltered(n, filter) => self.switch_to(n, filter).map(Response::Focused),
+            Cmd::Commit => {
+                self.commit();
+                Ok(Response::Ok)
+            }
+            Cmd::Back(n) => self.back(n).map(Response::Focused),
+            Cmd::Forward(n) => self.forward(n).map(Response::Focused),
+            Cmd::GetHistory => {
+                let (windows, empty_focus) = self.history();
+                Ok(Response::History {
+                    windows,
+                    empty_focus,
+                })
+            }
+            Cmd::GetHistoryEntries => Ok(Response::HistoryEntries(self.history_entries())),
+            Cmd::GetFrecencyHistory => Ok(Response::Windows(self.hist.frecency_order())),
+            Cmd::GetPredictedHistory => {
+                Ok(Response::Windows(self.hist.predicted_order(self.focused())))
+            }
+            Cmd::SwitchToWorkspace(n) => self.switch_to_workspace(n).map(|_| Response::Ok),
+            Cmd::GetWorkspaceHistory => Ok(Response::Workspaces(self.hist.workspace_names())),
+            Cmd::GetStats(period) => Ok(Response::Stats(self.usage.stats(period))),
+            Cmd::Subscribe => Err(ServerError::new(
+                ErrorCode::InvalidRequest,
+                "subscriptions are handled by the connection",
+            )),
+        };
+
+        res.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            Response::Error(e)
+        })
+    }
+}
+
+pub fn focus_server(opts: ServerOpts) -> Result<(), Box<dyn Error + Send + Sync>> {
     let (events_tx, events_rx) = mpsc::channel::<ServerEvent>();
 
     // i3 events
@@ -171,64 +986,221 @@ pub fn focus_server() -> Result<(), Box<dyn Error + Send + Sync>> {
         spawn_fallible(interrupt_listener, events_tx);
     }
 
-    let mut conn = swayipc::Connection::new()?;
-    let mut windows = VecDeque::new();
-    let mut empty_focus = true;
-    utils::get_focused_window(&conn.get_tree()?)
-        .map(|wid| {
-            windows.push_front(wid);
-        })
-        .ok();
+    let mut server = Server::new(opts)?;
 
-    for ev in events_rx {
-        match ev {
-            ServerEvent::I3Event(e) => {
-                if let swayipc::Event::Window(e) = e {
-                    match e.change {
-                        swayipc::WindowChange::Focus => {
-                            let cid = e.container.id;
-
-                            // dedupe, push front and truncate
-                            windows.retain(|v| *v != cid);
-                            windows.push_front(cid);
-                            windows.truncate(BUFFER_SIZE);
-                            empty_focus = false;
-                        }
-                        swayipc::WindowChange::Close => {
-                            let cid = e.container.id;
-
-                            // remove
-                            windows.retain(|v| *v != cid);
-                            empty_focus = true;
-                        }
-                        _ => {}
-                    }
-                } else if let swayipc::Event::Workspace(e) = e
-                    && e.change == swayipc::WorkspaceChange::Focus
-                {
-                    empty_focus = true;
+    loop {
+        server.expire();
+        server.notify();
+
+        let ev = match server.deadline() {
+            Some(deadline) => {
+                match events_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(ev) => ev,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
                 }
             }
-            ServerEvent::SwitchTo(n) => {
-                let n = if empty_focus {
-                    std::cmp::max(0, n - 1)
-                } else {
-                    n
-                };
-                focus_nth(&mut conn, &windows, n)
-                    .map_err(|e| eprintln!("{}", e))
-                    .ok();
+            None => match events_rx.recv() {
+                Ok(ev) => ev,
+                Err(_) => break,
+            },
+        };
+
+        match ev {
+            ServerEvent::I3Event(e) => server.handle_i3_event(e),
+            ServerEvent::WmReconnected => {
+                server.flush();
+                server.reconnect()?
             }
-            ServerEvent::GetHistory(chan) => {
-                let windows = Vec::from_iter(windows.iter().cloned());
-                chan.send((windows, empty_focus))?;
+            ServerEvent::Request(cmd, chan) => {
+                chan.send(server.handle_cmd(cmd))?;
             }
+            ServerEvent::Subscribe(chan) => server.subscribe(chan),
             ServerEvent::Stop(res) => {
+                server.flush();
                 res?;
                 break;
             }
         }
     }
 
+    server.flush();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+
+    use super::*;
+
+    fn request_error(value: serde_json::Value) -> ErrorCode {
+        parse_request(value).map(|_| ()).unwrap_err().code
+    }
+
+    #[test]
+    fn parse_versioned_request() {
+        let req = parse_request(serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "id": 7,
+            "cmd": { "SwitchTo": 2 },
+        }))
+        .unwrap();
+        assert_eq!(req.id, Some(7));
+        assert!(matches!(req.cmd, Cmd::SwitchTo(2)));
+
+        assert_eq!(
+            request_error(serde_json::json!({ "version": PROTOCOL_VERSION, "cmd": "Unknown" })),
+            ErrorCode::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn parse_other_version() {
+        assert_eq!(
+            request_error(serde_json::json!({ "version": PROTOCOL_VERSION + 1, "cmd": "Commit" })),
+            ErrorCode::VersionMismatch
+        );
+    }
+
+    #[test]
+    fn parse_unversioned_request() {
+        // bare commands of the clients predating the versioned protocol
+        assert_eq!(
+            request_error(serde_json::json!("GetHistory")),
+            ErrorCode::VersionMismatch
+        );
+        assert_eq!(
+            request_error(serde_json::json!({ "SwitchTo": 1 })),
+            ErrorCode::VersionMismatch
+        );
+        assert_eq!(
+            request_error(serde_json::json!({ "foo": "bar" })),
+            ErrorCode::InvalidRequest
+        );
+        assert_eq!(
+            request_error(serde_json::json!(42)),
+            ErrorCode::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn serve_legacy_request() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (events_tx, _events_rx) = mpsc::channel();
+        thread::spawn(move || serve_client(&server, &events_tx));
+
+        // no newline and no shutdown, like the clients of the first protocol
+        (&client).write_all(b"\"GetHistory\"").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(&client).read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert!(matches!(
+            response,
+            Response::Error(ServerError {
+                code: ErrorCode::VersionMismatch,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn serve_pipelined_requests() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (events_tx, events_rx) = mpsc::channel();
+        thread::spawn(move || serve_client(&server, &events_tx));
+        thread::spawn(move || {
+            for ev in events_rx {
+                if let ServerEvent::Request(_, chan) = ev {
+                    chan.send(Response::Ok).unwrap();
+                }
+            }
+        });
+
+        let requests = format!(
+            "{{\"version\":{v},\"id\":1,\"cmd\":\"Commit\"}}\n\
+             {{\"version\":{v},\"id\":2,\"cmd\":\"Unknown\"}}\n\
+             {{\"version\":{v},\"id\":3,\"cmd\":\"Commit\"}}\n",
+            v = PROTOCOL_VERSION
+        );
+        (&client).write_all(requests.as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let replies: Vec<Reply> = BufReader::new(&client)
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 3);
+        assert!(matches!(
+            replies[0],
+            Reply {
+                id: Some(1),
+                response: Response::Ok
+            }
+        ));
+        assert!(matches!(
+            replies[1],
+            Reply {
+                id: Some(2),
+                response: Response::Error(ServerError {
+                    code: ErrorCode::InvalidRequest,
+                    ..
+                })
+            }
+        ));
+        assert!(matches!(
+            replies[2],
+            Reply {
+                id: Some(3),
+                response: Response::Ok
+            }
+        ));
+    }
+
+    fn snapshot(windows: &[i64], workspace: &str) -> Snapshot {
+        Snapshot {
+            windows: windows.to_vec(),
+            workspace: Some(workspace.to_string()),
+        }
+    }
+
+    #[test]
+    fn changes_focus() {
+        let old = snapshot(&[1, 2, 3], "1");
+        let new = snapshot(&[3, 1, 2], "1");
+        assert_eq!(new.changes(&old), vec![HistoryEvent::Focused(3)]);
+    }
+
+    #[test]
+    fn changes_front_removed() {
+        let old = snapshot(&[1, 2, 3], "1");
+        let new = snapshot(&[2, 3], "1");
+        assert_eq!(new.changes(&old), vec![HistoryEvent::Removed(1)]);
+    }
+
+    #[test]
+    fn changes_removed_and_focused() {
+        let old = snapshot(&[1, 2, 3], "1");
+        let new = snapshot(&[3, 2], "2");
+        assert_eq!(
+            new.changes(&old),
+            vec![
+                HistoryEvent::WorkspaceFocused("2".to_string()),
+                HistoryEvent::Removed(1),
+                HistoryEvent::Focused(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_reordered() {
+        let old = snapshot(&[1, 2, 3], "1");
+        let new = snapshot(&[1, 3, 2], "1");
+        assert_eq!(
+            new.changes(&old),
+            vec![HistoryEvent::Reordered(vec![1, 3, 2])]
+        );
+        assert!(old.changes(&old).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use crate::ipc::Scope;
+
+/// Properties of a window which depend on where it sits in the tree
+#[derive(Debug, Clone, Default)]
+pub struct WindowInfo {
+    pub workspace: Option<String>,
+    pub output: Option<String>,
+    pub floating: bool,
+    /// Hidden in the scratchpad
+    pub scratchpad: bool,
+    /// On screen, as reported by sway or on a visible workspace with i3
+    pub visible: bool,
+    /// Direct parent container, not set for floating windows directly
+    /// under their workspace
+    pub parent: Option<i64>,
+    /// Closest tabbed or stacked container above the window
+    pub tabs: Option<i64>,
+}
+
+impl WindowInfo {
+    /// Container whose windows are siblings: the enclosing tabbed or stacked
+    /// container if any, the parent otherwise
+    pub fn container(&self) -> Option<i64> {
+        self.tabs.or(self.parent)
+    }
+}
+
+/// Name of the workspace holding the hidden scratchpad windows
+static SCRATCHPAD_WORKSPACE: &str = "__i3_scratch";
+
+/// Position of all the containers in the tree
+#[derive(Debug, Default)]
+pub struct Layout {
+    windows: HashMap<i64, WindowInfo>,
+    pub focused_workspace: Option<String>,
+    pub focused_output: Option<String>,
+    pub focused_container: Option<i64>,
+}
+
+impl Layout {
+    pub fn from_tree(root: &swayipc::Node) -> Layout {
+        let mut layout = Layout::default();
+
+        let mut expl = vec![(root, WindowInfo::default())];
+        while let Some((node, mut info)) = expl.pop() {
+            match node.node_type {
+                swayipc::NodeType::Output => info.output = node.name.clone(),
+                swayipc::NodeType::Workspace => {
+                    info.workspace = node.name.clone();
+                    info.scratchpad = node.name.as_deref() == Some(SCRATCHPAD_WORKSPACE);
+                }
+                swayipc::NodeType::Con | swayipc::NodeType::FloatingCon => {
+                    info.visible = node.visible.unwrap_or(info.visible);
+                    layout.windows.insert(node.id, info.clone());
+                }
+                _ => {}
+            }
+
+            if node.focused {
+                layout.focused_workspace = info.workspace.clone();
+                layout.focused_output = info.output.clone();
+                layout.focused_container = info.container();
+            }
+
+            for c in &node.nodes {
+                let mut info = info.clone();
+                info.parent = Some(node.id);
+                if matches!(
+                    node.layout,
+                    swayipc::NodeLayout::Tabbed | swayipc::NodeLayout::Stacked
+                ) {
+                    info.tabs = Some(node.id);
+                }
+                if c.node_type == swayipc::NodeType::Workspace {
+                    // the visible workspace of an output comes first in its
+                    // focus order
+                    info.visible = node.focus.first() == Some(&c.id)
+                        && c.name.as_deref() != Some(SCRATCHPAD_WORKSPACE);
+                }
+                expl.push((c, info));
+            }
+            for c in &node.floating_nodes {
+                let mut info = info.clone();
+                info.floating = true;
+                // floating windows are no siblings of the tiled windows of
+                // the workspace, nor of each other
+                info.parent = None;
+                info.tabs = None;
+                expl.push((c, info));
+            }
+        }
+
+        layout
+    }
+
+    pub fn get(&self, id: i64) -> Option<&WindowInfo> {
+        self.windows.get(&id)
+    }
+
+    /// Whether a window is hidden in the scratchpad
+    pub fn in_scratchpad(&self, id: i64) -> bool {
+        self.get(id).is_some_and(|i| i.scratchpad)
+    }
+
+    /// Whether a window is on screen
+    pub fn is_visible(&self, id: i64) -> bool {
+        self.get(id).is_some_and(|i| i.visible)
+    }
+
+    /// Whether a window is part of `scope`, relative to the focused window
+    pub fn in_scope(&self, id: i64, scope: Scope) -> bool {
+        let info = self.get(id);
+        match scope {
+            Scope::Global => true,
+            Scope::Workspace => {
+                let ws = info.and_then(|i| i.workspace.as_ref());
+                ws.is_some() && ws == self.focused_workspace.as_ref()
+            }
+            Scope::Output => {
+                let output = info.and_then(|i| i.output.as_ref());
+                output.is_some() && output == self.focused_output.as_ref()
+            }
+            Scope::Container => {
+                let container = info.and_then(|i| i.container());
+                container.is_some() && container == self.focused_container
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::node;
+
+    #[test]
+    fn floating_windows_are_not_siblings() {
+        let tree = node(json!({
+            "id": 1,
+            "type": "root",
+            "focus": [2],
+            "nodes": [{
+                "id": 2,
+                "type": "output",
+                "name": "eDP-1",
+                "focus": [3],
+                "nodes": [{
+                    "id": 3,
+                    "type": "workspace",
+                    "name": "1",
+                    "focus": [4],
+                    "nodes": [
+                        { "id": 4, "type": "con", "focused": true },
+                        { "id": 5, "type": "con" },
+                    ],
+                    "floating_nodes": [
+                        { "id": 6, "type": "floating_con" },
+                        { "id": 7, "type": "floating_con" },
+                    ],
+                }],
+            }],
+        }));
+        let layout = Layout::from_tree(&tree);
+
+        assert!(layout.in_scope(5, Scope::Container));
+        assert!(!layout.in_scope(6, Scope::Container));
+        assert!(layout.in_scope(6, Scope::Workspace));
+        assert!(layout.get(6).unwrap().floating);
+        assert_eq!(layout.get(7).unwrap().container(), None);
+    }
+
+    /// Two workspaces on an output, the first one being shown, and a window
+    /// in the scratchpad; `visible` is the property of the windows set by
+    /// sway
+    fn tree(visible: [Option<bool>; 2]) -> swayipc::Node {
+        node(json!({
+            "id": 1,
+            "type": "root",
+            "focus": [2, 3],
+            "nodes": [
+                {
+                    "id": 2,
+                    "type": "output",
+                    "name": "eDP-1",
+                    "focus": [12, 11],
+                    "nodes": [
+                        {
+                            "id": 11,
+                            "type": "workspace",
+                            "name": "1",
+                            "nodes": [{ "id": 21, "type": "con", "visible": visible[0] }],
+                        },
+                        {
+                            "id": 12,
+                            "type": "workspace",
+                            "name": "2",
+                            "nodes": [{ "id": 22, "type": "con", "visible": visible[1] }],
+                        },
+                    ],
+                },
+                {
+                    "id": 3,
+                    "type": "output",
+                    "name": "__i3",
+                    "focus": [13],
+                    "nodes": [{
+                        "id": 13,
+                        "type": "workspace",
+                        "name": "__i3_scratch",
+                        "floating_nodes": [{
+                            "id": 14,
+                            "type": "floating_con",
+                            "nodes": [{ "id": 23, "type": "con" }],
+                        }],
+                    }],
+                },
+            ],
+        }))
+    }
+
+    #[test]
+    fn visible_workspace_from_focus_order() {
+        let layout = Layout::from_tree(&tree([None, None]));
+
+        assert!(!layout.is_visible(21));
+        assert!(layout.is_visible(22));
+        // the scratchpad comes first in the focus order of its output but is
+        // never shown
+        assert!(!layout.is_visible(23));
+    }
+
+    #[test]
+    fn visible_property_overrides_focus_order() {
+        let layout = Layout::from_tree(&tree([Some(true), Some(false)]));
+
+        assert!(layout.is_visible(21));
+        assert!(!layout.is_visible(22));
+    }
+
+    #[test]
+    fn scratchpad_windows() {
+        let layout = Layout::from_tree(&tree([None, None]));
+
+        assert!(layout.in_scratchpad(23));
+        assert!(layout.get(23).unwrap().floating);
+        assert!(!layout.in_scratchpad(21));
+        assert!(!layout.in_scratchpad(22));
+    }
+}
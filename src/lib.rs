@@ -1,9 +1,18 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 
+mod history;
 pub mod ipc;
+pub mod layout;
+pub mod rules;
+mod state;
+#[cfg(test)]
+mod test_utils;
+mod usage;
 
-use crate::ipc::client::get_focus_history;
+use crate::ipc::client::{get_focus_history, get_frecency_history, get_predicted_history};
+use crate::layout::Layout;
+use crate::rules::ExcludeRules;
 
 fn extract_windows(root: &swayipc::Node) -> HashMap<i64, &swayipc::Node> {
     let mut out = HashMap::new();
@@ -39,9 +48,18 @@ pub mod utils {
     use std::env;
     use std::error::Error;
     use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     pub type IconsMap = HashMap<String, String>;
 
+    /// Milliseconds since the epoch
+    pub fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
     static DEFAULT_ICONS: &[(&str, &str)] = &[("Chromium", "chromium")];
 
     pub fn read_icons_map(icons_map: Option<&str>) -> IconsMap {
@@ -102,7 +120,7 @@ pub mod utils {
         None
     }
 
-    fn html_escape(instr: &str) -> String {
+    pub fn html_escape(instr: &str) -> String {
         instr
             .chars()
             .flat_map(|c| match c {
@@ -116,9 +134,12 @@ pub mod utils {
             .collect()
     }
 
+    /// Formats a window for the menu, `annotation` is appended after the title
     pub fn window_format_line(
         node: &swayipc::Node,
         icons_map: Option<&HashMap<String, String>>,
+        scratchpad: bool,
+        annotation: Option<&str>,
     ) -> String {
         let mut marks = node.marks.join("][");
         if !node.marks.is_empty() {
@@ -137,15 +158,39 @@ pub mod utils {
             name = " - ".to_string() + n;
         }
 
+        let mut annot = "".to_string();
+        if scratchpad {
+            annot += " <i>scratchpad</i>";
+        }
+        if let Some(a) = annotation {
+            annot += &format!(" <span alpha=\"50%\">({})</span>", html_escape(a));
+        }
+
         format!(
-            "{}{}<span weight=\"bold\">{}</span>{}\n",
+            "{}{}<span weight=\"bold\">{}</span>{}{}\n",
             html_escape(&disp_id.unwrap_or("Container".to_string())),
             html_escape(&marks),
             html_escape(&name),
+            annot,
             plus
         )
     }
 
+    /// Command bringing a window up, windows hidden in the scratchpad are
+    /// shown rather than focused
+    pub fn focus_command(wid: i64, scratchpad: bool) -> String {
+        if scratchpad {
+            format!("[con_id={}] scratchpad show", wid)
+        } else {
+            format!("[con_id={}] focus", wid)
+        }
+    }
+
+    /// Moves the urgent windows first, keeping the order otherwise
+    pub fn urgent_first(windows: &mut [swayipc::Node]) {
+        windows.sort_by_key(|w| !w.urgent);
+    }
+
     pub fn get_focused_window(root: &swayipc::Node) -> Result<i64, Box<dyn Error + Send + Sync>> {
         let mut node = root;
 
@@ -161,20 +206,36 @@ pub mod utils {
 pub enum WindowsSortStyle {
     CurrentLast,
     CurrentFirst,
+    /// Most frequently and recently used first, current window last
+    Frecency,
+    /// Most likely to follow the current window first, current window last
+    Predicted,
 }
 
 /// Returns the list of current windows in most-recently-used order
 ///
 /// It will try to connect to the i3-focus-last server if available and will
-/// default to the order returned by the WM otherwise.
+/// default to the order returned by the WM otherwise. Only the windows in
+/// `scope` are returned and windows matching the exclusion rules are left
+/// out.
 pub fn get_windows_by_history(
     conn: &mut swayipc::Connection,
     sort_style: WindowsSortStyle,
+    scope: Scope,
+    rules: &ExcludeRules,
 ) -> Result<Vec<swayipc::Node>, Box<dyn Error + Send + Sync>> {
     let t = conn.get_tree()?;
-    let ws = extract_windows(&t);
-
-    let (hist, empty_focus) = get_focus_history().unwrap_or_else(|e| {
+    let layout = Layout::from_tree(&t);
+    let mut ws = extract_windows(&t);
+    let excluded = rules.excluded_windows(&t, &layout);
+    ws.retain(|i, _| !excluded.contains(i) && layout.in_scope(*i, scope));
+
+    let (hist, empty_focus) = match sort_style {
+        WindowsSortStyle::Frecency => get_frecency_history().map(|h| (h, false)),
+        WindowsSortStyle::Predicted => get_predicted_history().map(|h| (h, false)),
+        _ => get_focus_history(),
+    }
+    .unwrap_or_else(|e| {
         eprintln!(
             "warning: could not get focus history: \"{}\", order will be arbitrary",
             e
@@ -197,7 +258,17 @@ pub fn get_windows_by_history(
         }
     }
 
-    if !hist.is_empty() {
+    if matches!(
+        sort_style,
+        WindowsSortStyle::Frecency | WindowsSortStyle::Predicted
+    ) {
+        if let Ok(fid) = utils::get_focused_window(&t)
+            && let Some(pos) = ordered_windows.iter().position(|w| w.id == fid)
+        {
+            let current = ordered_windows.remove(pos);
+            ordered_windows.push(current);
+        }
+    } else if !hist.is_empty() {
         if sort_style == WindowsSortStyle::CurrentLast && !empty_focus {
             ordered_windows.rotate_left(1);
         } else if sort_style == WindowsSortStyle::CurrentFirst && empty_focus {
@@ -209,5 +280,13 @@ pub fn get_windows_by_history(
 }
 
 // re-exports
-pub use crate::ipc::client::focus_nth_last_client;
+pub use crate::ipc::client::{
+    Client, back_client, commit_switch_client, focus_nth_last_client,
+    focus_nth_last_filtered_client, focus_nth_last_workspace_client, forward_client,
+    get_history_entries, get_stats, get_workspace_history, watch_history,
+};
 pub use crate::ipc::server::{ServerOpts, focus_server};
+pub use crate::ipc::{
+    Cmd, ErrorCode, FocusStats, HistoryEntry, HistoryEvent, Notification, Response, Scope,
+    ServerError, StatsPeriod, SwitchFilter, UsageEntry, UsageStats,
+};
@@ -6,12 +6,20 @@ use std::error::Error;
 use std::io;
 use std::io::Write;
 use std::process::{Command, Stdio};
+use std::str::FromStr;
 use std::str::from_utf8;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-use i3_focus_last::utils;
+use i3_focus_last::layout::Layout;
 use i3_focus_last::{
-    ServerOpts, WindowsSortStyle, focus_nth_last_client, focus_server, get_windows_by_history,
+    Client, Scope, ServerOpts, StatsPeriod, SwitchFilter, UsageEntry, WindowsSortStyle,
+    back_client, commit_switch_client, focus_nth_last_client, focus_nth_last_filtered_client,
+    focus_nth_last_workspace_client, focus_server, forward_client, get_history_entries, get_stats,
+    get_windows_by_history, get_workspace_history, watch_history,
 };
+use i3_focus_last::{rules, utils};
 
 #[derive(Debug, Options)]
 pub struct MenuOpts {
@@ -26,6 +34,33 @@ pub struct MenuOpts {
         default = "~/.config/i3-focus-last/icons.json"
     )]
     icons_map: String,
+
+    #[options(
+        help = "path to exclusion rules",
+        default = "~/.config/i3-focus-last/exclude.json"
+    )]
+    exclude_rules: String,
+
+    #[options(
+        help = "windows to list: global, workspace, output or container",
+        default = "global"
+    )]
+    scope: Scope,
+
+    #[options(help = "show when each window was last used")]
+    show_times: bool,
+
+    #[options(help = "sort by frecency (frequency and recency) instead of recency")]
+    frecency: bool,
+
+    #[options(help = "sort by likelihood of following the focused window")]
+    predicted: bool,
+
+    #[options(help = "list urgent windows first")]
+    urgent_first: bool,
+
+    #[options(help = "leave out the windows already on screen")]
+    skip_visible: bool,
 }
 
 #[derive(Debug, Options)]
@@ -36,12 +71,147 @@ enum ProgCommand {
     Server(ServerOpts),
     #[options(help = "start menu")]
     Menu(MenuOpts),
+    #[options(help = "go back in the history")]
+    Back(NavOpts),
+    #[options(help = "go forward in the history, after going back")]
+    Forward(NavOpts),
+    #[options(help = "switch to a recent workspace")]
+    Workspace(WorkspaceOpts),
+    #[options(help = "report the time spent per application and workspace")]
+    Stats(StatsOpts),
+    #[options(help = "print the changes of the history as JSON lines")]
+    Watch(WatchOpts),
+    #[options(help = "show the window to switch to in a status bar")]
+    Bar(BarOpts),
 }
 
 #[derive(Debug, Options)]
 struct SwitchOpts {
     #[options(help = "nth window to focus", no_long, short = "n", default = "1")]
     count: usize,
+
+    #[options(help = "end the current cycle of switches")]
+    commit: bool,
+
+    #[options(
+        help = "windows to consider: global, workspace, output or container",
+        default = "global"
+    )]
+    scope: Scope,
+
+    #[options(help = "only windows of the same application as the focused one")]
+    same_app: bool,
+
+    #[options(help = "windows most likely to follow the focused one first")]
+    predicted: bool,
+
+    #[options(help = "only urgent windows, most recently urgent first")]
+    urgent: bool,
+
+    #[options(help = "leave out the windows hidden in the scratchpad")]
+    no_scratchpad: bool,
+
+    #[options(help = "leave out the windows already on screen")]
+    skip_visible: bool,
+
+    #[options(help = "only windows outside of the focused workspace")]
+    other_workspace: bool,
+
+    #[options(help = "only floating windows")]
+    floating: bool,
+
+    #[options(help = "only tiled windows")]
+    tiled: bool,
+}
+
+#[derive(Debug, Options)]
+struct NavOpts {
+    #[options(
+        help = "number of windows to skip",
+        no_long,
+        short = "n",
+        default = "1"
+    )]
+    count: usize,
+}
+
+#[derive(Debug, Options)]
+struct WorkspaceOpts {
+    #[options(help = "nth workspace to focus", no_long, short = "n", default = "1")]
+    count: usize,
+
+    #[options(help = "list workspaces by recency instead")]
+    list: bool,
+}
+
+#[derive(Debug)]
+enum StatsFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            "csv" => Ok(StatsFormat::Csv),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Options)]
+struct StatsOpts {
+    #[options(help = "period to report: day, hour or start", default = "day")]
+    period: StatsPeriod,
+
+    #[options(help = "output format: table, json or csv", default = "table")]
+    format: StatsFormat,
+}
+
+#[derive(Debug, Options)]
+struct WatchOpts {}
+
+#[derive(Debug)]
+enum BarFormat {
+    I3bar,
+    Waybar,
+    I3blocks,
+}
+
+impl FromStr for BarFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i3bar" => Ok(BarFormat::I3bar),
+            "waybar" => Ok(BarFormat::Waybar),
+            "i3blocks" => Ok(BarFormat::I3blocks),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Options)]
+struct BarOpts {
+    #[options(
+        help = "output format: i3bar, waybar or i3blocks",
+        default = "i3blocks"
+    )]
+    format: BarFormat,
+
+    #[options(help = "text before the window title", default = "Tab → ")]
+    prefix: String,
+
+    #[options(
+        help = "path to icons map",
+        default = "~/.config/i3-focus-last/icons.json"
+    )]
+    icons_map: String,
 }
 
 #[derive(Debug, Options)]
@@ -56,10 +226,24 @@ struct ProgOptions {
     command: Option<ProgCommand>,
 }
 
+/// Formats the time elapsed since `ms` (since the epoch), like "5 min ago"
+fn format_age(ms: u64) -> String {
+    let secs = utils::now_ms().saturating_sub(ms) / 1000;
+
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} min ago", secs / 60),
+        3600..86400 => format!("{} h ago", secs / 3600),
+        _ => format!("{} d ago", secs / 86400),
+    }
+}
+
 fn choose_with_menu(
     menu: &str,
     icons_map: &HashMap<String, String>,
     windows: &[swayipc::Node],
+    layout: &Layout,
+    annotations: &HashMap<i64, String>,
 ) -> Result<Option<usize>, Box<dyn Error + Send + Sync>> {
     // TODO: better split
     let cmd: Vec<&str> = menu.split(' ').collect();
@@ -77,7 +261,13 @@ fn choose_with_menu(
             .as_mut()
             .ok_or_else(|| io::Error::other("no stdin"))?;
         for w in windows {
-            let line = utils::window_format_line(w, Some(icons_map));
+            let annotation = annotations.get(&w.id).map(|a| a.as_str());
+            let line = utils::window_format_line(
+                w,
+                Some(icons_map),
+                layout.in_scratchpad(w.id),
+                annotation,
+            );
             stdin.write_all(line.as_bytes())?;
         }
     }
@@ -96,18 +286,294 @@ fn choose_with_menu(
 
 fn focus_menu(menu_opts: MenuOpts) -> Result<(), Box<dyn Error + Send + Sync>> {
     let icons_map = utils::read_icons_map(Some(&menu_opts.icons_map));
+    let rules = rules::read_exclude_rules(Some(&menu_opts.exclude_rules));
 
     let mut conn = swayipc::Connection::new()?;
 
-    let ordered_windows = get_windows_by_history(&mut conn, WindowsSortStyle::CurrentLast)?;
+    let sort_style = if menu_opts.predicted {
+        WindowsSortStyle::Predicted
+    } else if menu_opts.frecency {
+        WindowsSortStyle::Frecency
+    } else {
+        WindowsSortStyle::CurrentLast
+    };
+    let mut ordered_windows =
+        get_windows_by_history(&mut conn, sort_style, menu_opts.scope, &rules)?;
+    let layout = Layout::from_tree(&conn.get_tree()?);
+    if menu_opts.skip_visible {
+        ordered_windows.retain(|w| !layout.is_visible(w.id));
+    }
+    if menu_opts.urgent_first {
+        utils::urgent_first(&mut ordered_windows);
+    }
 
-    let choice = choose_with_menu(&menu_opts.menu, &icons_map, &ordered_windows)?;
+    let mut annotations = HashMap::new();
+    if menu_opts.show_times {
+        match get_history_entries() {
+            Ok(entries) => {
+                for e in entries.iter().filter(|e| e.stats.last_focused > 0) {
+                    annotations.insert(e.id, format_age(e.stats.last_focused));
+                }
+            }
+            Err(e) => eprintln!("warning: could not get history entries: \"{}\"", e),
+        }
+    }
+
+    let choice = choose_with_menu(
+        &menu_opts.menu,
+        &icons_map,
+        &ordered_windows,
+        &layout,
+        &annotations,
+    )?;
 
     match choice {
         None => (),
         Some(choice) => {
             let wid = ordered_windows[choice].id;
-            conn.run_command(format!("[con_id={}] focus", wid).as_str())?;
+            conn.run_command(utils::focus_command(wid, layout.in_scratchpad(wid)))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn list_workspaces() -> Result<(), Box<dyn Error + Send + Sync>> {
+    for name in get_workspace_history()? {
+        println!("{}", name);
+    }
+
+    Ok(())
+}
+
+/// Formats a duration in ms like "1h 05m"
+fn format_duration(ms: u64) -> String {
+    let mins = ms / 60000;
+    if mins < 60 {
+        format!("{}m {:02}s", mins, ms / 1000 % 60)
+    } else {
+        format!("{}h {:02}m", mins / 60, mins % 60)
+    }
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn print_stats(opts: StatsOpts) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stats = get_stats(opts.period)?;
+    let sections: [(&str, &[UsageEntry]); 2] = [
+        ("application", &stats.apps),
+        ("workspace", &stats.workspaces),
+    ];
+
+    match opts.format {
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        StatsFormat::Csv => {
+            println!("kind,name,focused_ms,switches");
+            for (kind, entries) in sections {
+                for e in entries {
+                    println!(
+                        "{},{},{},{}",
+                        kind,
+                        csv_escape(&e.name),
+                        e.focused_ms,
+                        e.switches
+                    );
+                }
+            }
+        }
+        StatsFormat::Table => {
+            for (k, (kind, entries)) in sections.iter().enumerate() {
+                if k > 0 {
+                    println!();
+                }
+                let width = entries
+                    .iter()
+                    .map(|e| e.name.chars().count())
+                    .chain([kind.len()])
+                    .max()
+                    .unwrap_or(0);
+                println!("{:width$}  {:>8}  {:>8}", kind, "focused", "switches");
+                for e in entries.iter() {
+                    println!(
+                        "{:width$}  {:>8}  {:>8}",
+                        e.name,
+                        format_duration(e.focused_ms),
+                        e.switches
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn watch() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stdout = io::stdout();
+    watch_history(|n| {
+        writeln!(stdout, "{}", serde_json::to_string(&n)?)?;
+        Ok(())
+    })
+}
+
+/// Changes shown by the bar
+enum BarEvent {
+    History,
+    Title(Box<swayipc::Node>),
+}
+
+/// Connections kept open by the bar between updates
+#[derive(Default)]
+struct BarConnections {
+    client: Option<Client>,
+    conn: Option<swayipc::Connection>,
+}
+
+impl BarConnections {
+    /// The window a switch without argument would focus
+    fn previous_window(&mut self) -> Result<Option<swayipc::Node>, Box<dyn Error + Send + Sync>> {
+        let res = (|| {
+            let client = match &mut self.client {
+                Some(c) => c,
+                None => self.client.insert(Client::connect()?),
+            };
+            let (windows, empty_focus) = client.focus_history()?;
+            let skip = if empty_focus { 0 } else { 1 };
+            let Some(wid) = windows.get(skip) else {
+                return Ok(None);
+            };
+
+            let conn = match &mut self.conn {
+                Some(c) => c,
+                None => self.conn.insert(swayipc::Connection::new()?),
+            };
+            Ok(conn.get_tree()?.find(|n| n.id == *wid))
+        })();
+
+        // both may have gone away with a restart of the window manager
+        if res.is_err() {
+            *self = BarConnections::default();
+        }
+        res
+    }
+}
+
+/// Formats the previous window as one update of the bar
+fn bar_block(
+    opts: &BarOpts,
+    icons_map: &utils::IconsMap,
+    window: Option<&swayipc::Node>,
+) -> String {
+    let title = window.and_then(|w| w.name.clone().or_else(|| utils::node_display_id(w)));
+    let text = title
+        .map(|t| format!("{}{}", opts.prefix, t))
+        .unwrap_or_default();
+
+    match opts.format {
+        BarFormat::I3blocks => text,
+        BarFormat::Waybar => serde_json::json!({
+            "text": utils::html_escape(&text),
+            "tooltip": window.and_then(utils::node_display_id),
+            "alt": window.and_then(|w| utils::node_icon_name(w, icons_map)),
+            "class": if window.is_some() { "previous" } else { "empty" },
+        })
+        .to_string(),
+        BarFormat::I3bar => {
+            serde_json::json!([{ "name": "i3-focus-last", "full_text": text }]).to_string()
+        }
+    }
+}
+
+/// Sends the changes of the history, waiting for the server to come back when
+/// it restarts with the window manager
+fn bar_history_listener(chan: mpsc::Sender<BarEvent>) {
+    loop {
+        let res = watch_history(|_| Ok(chan.send(BarEvent::History)?));
+        if let Err(e) = res {
+            eprintln!("warning: lost the server: {}", e);
+        }
+        thread::sleep(Duration::from_secs(1));
+        if chan.send(BarEvent::History).is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends the title changes of all windows
+fn bar_title_listener(chan: mpsc::Sender<BarEvent>) {
+    loop {
+        let events =
+            swayipc::Connection::new().and_then(|c| c.subscribe([swayipc::EventType::Window]));
+        match events {
+            Ok(events) => {
+                for ev in events {
+                    match ev {
+                        Ok(swayipc::Event::Window(e))
+                            if e.change == swayipc::WindowChange::Title =>
+                        {
+                            if chan.send(BarEvent::Title(Box::new(e.container))).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(_) => break,
+                    }
+                }
+            }
+            Err(e) => eprintln!("warning: could not subscribe to window events: {}", e),
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn bar(opts: BarOpts) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let icons_map = utils::read_icons_map(Some(&opts.icons_map));
+
+    let (events_tx, events_rx) = mpsc::channel();
+    {
+        let events_tx = events_tx.clone();
+        thread::spawn(move || bar_history_listener(events_tx));
+    }
+    thread::spawn(move || bar_title_listener(events_tx));
+
+    if let BarFormat::I3bar = opts.format {
+        println!("{{\"version\":1}}");
+        println!("[");
+    }
+
+    let mut conns = BarConnections::default();
+    let mut window = None;
+    let mut last: Option<String> = None;
+    for ev in std::iter::once(BarEvent::History).chain(events_rx) {
+        match ev {
+            BarEvent::History => {
+                window = conns.previous_window().unwrap_or_else(|e| {
+                    eprintln!("warning: could not get the previous window: {}", e);
+                    None
+                });
+            }
+            BarEvent::Title(node) => {
+                if window.as_ref().is_some_and(|w| w.id == node.id) {
+                    window = Some(*node);
+                }
+            }
+        }
+
+        let block = bar_block(&opts, &icons_map, window.as_ref());
+        if last.as_ref() != Some(&block) {
+            // i3bar expects an endless JSON array
+            let sep = match (&opts.format, &last) {
+                (BarFormat::I3bar, Some(_)) => ",",
+                _ => "",
+            };
+            println!("{}{}", sep, block);
+            last = Some(block);
         }
     }
 
@@ -123,10 +589,33 @@ fn main() -> Result<(), String> {
     }
 
     let r = match opts.command {
-        Some(ProgCommand::Server(_)) => focus_server(),
-        Some(ProgCommand::Switch(o)) => focus_nth_last_client(o.count),
+        Some(ProgCommand::Server(o)) => focus_server(o),
+        Some(ProgCommand::Switch(o)) if o.commit => commit_switch_client(),
+        Some(ProgCommand::Switch(o)) if o.floating && o.tiled => {
+            Err(From::from("--floating and --tiled can't be used together"))
+        }
+        Some(ProgCommand::Switch(o)) => {
+            let filter = SwitchFilter {
+                scope: o.scope,
+                same_app: o.same_app,
+                predicted: o.predicted,
+                urgent: o.urgent,
+                no_scratchpad: o.no_scratchpad,
+                skip_visible: o.skip_visible,
+                other_workspace: o.other_workspace,
+                floating: (o.floating || o.tiled).then_some(o.floating),
+            };
+            focus_nth_last_filtered_client(o.count, filter).map(|_| ())
+        }
         Some(ProgCommand::Menu(m)) => focus_menu(m),
-        _ => focus_nth_last_client(1),
+        Some(ProgCommand::Back(o)) => back_client(o.count).map(|_| ()),
+        Some(ProgCommand::Forward(o)) => forward_client(o.count).map(|_| ()),
+        Some(ProgCommand::Workspace(o)) if o.list => list_workspaces(),
+        Some(ProgCommand::Workspace(o)) => focus_nth_last_workspace_client(o.count),
+        Some(ProgCommand::Stats(o)) => print_stats(o),
+        Some(ProgCommand::Watch(_)) => watch(),
+        Some(ProgCommand::Bar(o)) => bar(o),
+        _ => focus_nth_last_client(1).map(|_| ()),
     };
 
     if let Err(ref e) = r {
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::extract_windows;
+use crate::layout::{Layout, WindowInfo};
+
+fn deserialize_regex<'de, D>(de: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(de)?;
+    s.map(|s| Regex::new(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// A set of criteria matched against windows
+///
+/// A window matches the rule when it satisfies all the criteria that are
+/// set, a rule without any criteria never matches.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    app_id: Option<String>,
+    class: Option<String>,
+    instance: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    title: Option<Regex>,
+    floating: Option<bool>,
+    workspace: Option<String>,
+}
+
+impl Rule {
+    pub fn matches(&self, node: &swayipc::Node, info: Option<&WindowInfo>) -> bool {
+        let props = node.window_properties.as_ref();
+        let info = info.cloned().unwrap_or_default();
+
+        let checks = [
+            self.app_id
+                .as_ref()
+                .map(|a| node.app_id.as_ref() == Some(a)),
+            self.class
+                .as_ref()
+                .map(|c| props.and_then(|p| p.class.as_ref()) == Some(c)),
+            self.instance
+                .as_ref()
+                .map(|i| props.and_then(|p| p.instance.as_ref()) == Some(i)),
+            self.title
+                .as_ref()
+                .map(|t| node.name.as_ref().is_some_and(|n| t.is_match(n))),
+            self.floating.map(|f| info.floating == f),
+            self.workspace
+                .as_ref()
+                .map(|w| info.workspace.as_ref() == Some(w)),
+        ];
+
+        let mut set = checks.iter().flatten().peekable();
+        set.peek().is_some() && set.all(|c| *c)
+    }
+}
+
+/// Rules for windows that never enter the focus history
+#[derive(Debug, Default)]
+pub struct ExcludeRules(Vec<Rule>);
+
+impl ExcludeRules {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn excluded(&self, node: &swayipc::Node, info: Option<&WindowInfo>) -> bool {
+        self.0.iter().any(|r| r.matches(node, info))
+    }
+
+    /// Returns the ids of the windows of the tree matching any of the rules
+    pub fn excluded_windows(&self, root: &swayipc::Node, layout: &Layout) -> HashSet<i64> {
+        if self.is_empty() {
+            return HashSet::new();
+        }
+
+        extract_windows(root)
+            .into_values()
+            .filter(|n| self.excluded(n, layout.get(n.id)))
+            .map(|n| n.id)
+            .collect()
+    }
+}
+
+pub fn read_exclude_rules(exclude_rules: Option<&str>) -> ExcludeRules {
+    let exclude_rules = exclude_rules.unwrap_or("~/.config/i3-focus-last/exclude.json");
+
+    let r = || -> Result<ExcludeRules, Box<dyn Error>> {
+        let exclude_rules = exclude_rules.replace('~', &env::var("HOME")?);
+
+        let f = match fs::File::open(exclude_rules) {
+            Ok(f) => f,
+            // no rules configured
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ExcludeRules::default()),
+            Err(e) => return Err(From::from(e)),
+        };
+        Ok(ExcludeRules(serde_json::from_reader(f)?))
+    }();
+
+    r.unwrap_or_else(|e| {
+        eprintln!("Could not read exclusion rules: {}", e);
+        ExcludeRules::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::node;
+
+    fn rule(criteria: serde_json::Value) -> Rule {
+        serde_json::from_value(criteria).unwrap()
+    }
+
+    fn info(workspace: &str, floating: bool) -> WindowInfo {
+        WindowInfo {
+            workspace: Some(workspace.to_string()),
+            floating,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn match_all_criteria() {
+        let mpv = node(json!({
+            "id": 1,
+            "name": "video.mkv - mpv",
+            "window_properties": { "class": "mpv", "instance": "gl" },
+        }));
+        let r = rule(json!({ "class": "mpv", "floating": true }));
+
+        assert!(r.matches(&mpv, Some(&info("1", true))));
+        assert!(!r.matches(&mpv, Some(&info("1", false))));
+        assert!(!r.matches(&mpv, None));
+        assert!(
+            rule(json!({ "instance": "gl", "workspace": "1" }))
+                .matches(&mpv, Some(&info("1", false)))
+        );
+        assert!(!rule(json!({ "app_id": "mpv" })).matches(&mpv, None));
+    }
+
+    #[test]
+    fn match_title_regex() {
+        let pip = node(json!({ "id": 1, "app_id": "firefox", "name": "Picture-in-Picture" }));
+        let r = rule(json!({ "title": "^Picture-in-Picture$" }));
+
+        assert!(r.matches(&pip, None));
+        assert!(!r.matches(&node(json!({ "id": 2, "app_id": "firefox" })), None));
+        assert!(rule(json!({ "app_id": "firefox", "title": "Picture" })).matches(&pip, None));
+    }
+
+    #[test]
+    fn empty_rule_never_matches() {
+        let w = node(json!({ "id": 1, "app_id": "foot" }));
+        assert!(!rule(json!({})).matches(&w, Some(&info("1", false))));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(serde_json::from_value::<Rule>(json!({ "title": "(" })).is_err());
+        assert!(serde_json::from_value::<Rule>(json!({ "role": "popup" })).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::Transitions;
+use crate::ipc::FocusStats;
+use crate::utils;
+
+/// Properties used to find a window again after a restart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WindowIdentity {
+    pub id: i64,
+    /// X11 window id
+    pub window: Option<i64>,
+    pub pid: Option<i32>,
+    /// app_id or X11 class
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+}
+
+/// Entry of the history file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedWindow {
+    #[serde(flatten)]
+    pub ident: WindowIdentity,
+    #[serde(default)]
+    pub stats: FocusStats,
+}
+
+impl WindowIdentity {
+    pub fn from_node(node: &swayipc::Node) -> WindowIdentity {
+        WindowIdentity {
+            id: node.id,
+            window: node.window,
+            pid: node.pid,
+            app_id: utils::node_display_id(node),
+            title: node.name.clone(),
+        }
+    }
+
+    /// Checks that a node with the same con_id is really the same window
+    fn same_con(&self, node: &swayipc::Node) -> bool {
+        if self.id != node.id {
+            return false;
+        }
+        match (self.window, node.window) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.app_id == utils::node_display_id(node),
+        }
+    }
+
+    fn same_window(&self, node: &swayipc::Node) -> bool {
+        self.window.is_some() && self.window == node.window
+    }
+
+    fn same_pid_title(&self, node: &swayipc::Node) -> bool {
+        self.pid.is_some() && self.pid == node.pid && self.title == node.name
+    }
+
+    fn same_app_title(&self, node: &swayipc::Node) -> bool {
+        self.app_id.is_some()
+            && self.app_id == utils::node_display_id(node)
+            && self.title == node.name
+    }
+}
+
+/// Finds the live windows corresponding to saved identities
+///
+/// The result is aligned with `saved`, with `None` for entries that can't be
+/// matched to a window. Matching is tried by con_id first, then by X11
+/// window id, pid and finally app_id and title.
+pub fn match_windows(
+    saved: &[&WindowIdentity],
+    windows: &HashMap<i64, &swayipc::Node>,
+) -> Vec<Option<i64>> {
+    let matchers: [fn(&WindowIdentity, &swayipc::Node) -> bool; 4] = [
+        WindowIdentity::same_con,
+        WindowIdentity::same_window,
+        WindowIdentity::same_pid_title,
+        WindowIdentity::same_app_title,
+    ];
+
+    let mut found: Vec<Option<i64>> = vec![None; saved.len()];
+    let mut claimed = HashSet::new();
+
+    for matcher in matchers {
+        for (k, ident) in saved.iter().enumerate() {
+            if found[k].is_some() {
+                continue;
+            }
+            let m = windows
+                .values()
+                .filter(|n| !claimed.contains(&n.id))
+                .find(|n| matcher(ident, n));
+            if let Some(n) = m {
+                found[k] = Some(n.id);
+                claimed.insert(n.id);
+            }
+        }
+    }
+
+    found
+}
+
+/// Returns the directory where the server keeps its state
+pub fn state_dir() -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let base = match env::var("XDG_STATE_HOME") {
+        Ok(d) if !d.is_empty() => PathBuf::from(d),
+        _ => PathBuf::from(env::var("HOME")?).join(".local/state"),
+    };
+    Ok(base.join("i3-focus-last"))
+}
+
+/// Replaces the content of a file through a temporary file, synced before
+/// the rename so that a crash leaves either the old or the new content
+fn write_file(filename: &Path, content: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(dir) = filename.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = filename.with_extension("json.tmp");
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(content)?;
+    f.sync_all()?;
+    fs::rename(tmp, filename)?;
+
+    Ok(())
+}
+
+fn history_filename() -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    Ok(state_dir()?.join("history.json"))
+}
+
+/// Reads the focus history saved by a previous server
+pub fn load_history() -> Result<Vec<SavedWindow>, Box<dyn Error + Send + Sync>> {
+    let filename = history_filename()?;
+    if !filename.exists() {
+        return Ok(vec![]);
+    }
+
+    let f = fs::File::open(filename)?;
+    Ok(serde_json::from_reader(f)?)
+}
+
+/// Saves the focus history, most recent first
+pub fn save_history(history: &[SavedWindow]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_file(&history_filename()?, &serde_json::to_vec(history)?)
+}
+
+fn transitions_filename() -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    Ok(state_dir()?.join("transitions.json"))
+}
+
+/// Reads the transitions between applications learnt by a previous server
+pub fn load_transitions() -> Result<Transitions, Box<dyn Error + Send + Sync>> {
+    let filename = transitions_filename()?;
+    if !filename.exists() {
+        return Ok(Transitions::default());
+    }
+
+    let f = fs::File::open(filename)?;
+    Ok(serde_json::from_reader(f)?)
+}
+
+/// Saves the transitions between applications
+pub fn save_transitions(transitions: &Transitions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_file(&transitions_filename()?, &serde_json::to_vec(transitions)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::node;
+
+    fn ident(fields: serde_json::Value) -> WindowIdentity {
+        WindowIdentity::from_node(&node(fields))
+    }
+
+    fn matched(saved: &[WindowIdentity], live: &[swayipc::Node]) -> Vec<Option<i64>> {
+        let saved: Vec<_> = saved.iter().collect();
+        let live = live.iter().map(|n| (n.id, n)).collect();
+        match_windows(&saved, &live)
+    }
+
+    #[test]
+    fn match_same_con() {
+        let saved = [ident(json!({ "id": 1, "app_id": "foot" }))];
+        let live = [
+            node(json!({ "id": 1, "app_id": "foot" })),
+            node(json!({ "id": 2, "app_id": "foot" })),
+        ];
+        assert_eq!(matched(&saved, &live), vec![Some(1)]);
+    }
+
+    #[test]
+    fn reused_con_id() {
+        // con_id 1 now belongs to another window, the X11 id tells them apart
+        let saved = [ident(json!({ "id": 1, "window": 10, "name": "vim" }))];
+        let live = [
+            node(json!({ "id": 1, "window": 11, "name": "vim" })),
+            node(json!({ "id": 5, "window": 10, "name": "vim" })),
+        ];
+        assert_eq!(matched(&saved, &live), vec![Some(5)]);
+    }
+
+    #[test]
+    fn match_after_restart() {
+        let saved = [
+            ident(json!({ "id": 1, "pid": 42, "name": "mail" })),
+            ident(json!({ "id": 2, "app_id": "foot", "name": "~" })),
+            ident(json!({ "id": 3, "app_id": "foot", "name": "~" })),
+            ident(json!({ "id": 4, "app_id": "gone" })),
+        ];
+        let live = [
+            node(json!({ "id": 11, "pid": 42, "name": "mail" })),
+            node(json!({ "id": 12, "app_id": "foot", "name": "~" })),
+        ];
+        // a live window is only claimed once
+        assert_eq!(matched(&saved, &live), vec![Some(11), Some(12), None, None]);
+    }
+
+    #[test]
+    fn stronger_match_first() {
+        // the title match of the first entry must not steal the window
+        // identified by its X11 id for the second one
+        let saved = [
+            ident(json!({ "id": 1, "app_id": "term", "name": "~" })),
+            ident(json!({ "id": 2, "window": 20, "app_id": "term", "name": "~" })),
+        ];
+        let live = [node(
+            json!({ "id": 12, "window": 20, "app_id": "term", "name": "~" }),
+        )];
+        assert_eq!(matched(&saved, &live), vec![None, Some(12)]);
+    }
+}
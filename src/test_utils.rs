@@ -0,0 +1,41 @@
+//! Helpers shared by the unit tests
+
+use serde_json::{Value, json};
+
+/// Fills the fields missing from a node and its children with the values of
+/// an empty container
+fn with_defaults(fields: Value) -> Value {
+    let rect = json!({"x": 0, "y": 0, "width": 0, "height": 0});
+    let mut node = json!({
+        "id": 0,
+        "type": "con",
+        "border": "none",
+        "current_border_width": 0,
+        "layout": "none",
+        "rect": rect,
+        "window_rect": rect,
+        "deco_rect": rect,
+        "geometry": rect,
+        "urgent": false,
+        "focused": false,
+        "focus": [],
+        "nodes": [],
+        "floating_nodes": [],
+        "sticky": false,
+    });
+    let obj = node.as_object_mut().unwrap();
+    if let Value::Object(fields) = fields {
+        obj.extend(fields);
+    }
+    for children in ["nodes", "floating_nodes"] {
+        if let Some(Value::Array(c)) = obj.get_mut(children) {
+            *c = c.drain(..).map(with_defaults).collect();
+        }
+    }
+    node
+}
+
+/// Builds a node from the fields given in `fields`, children included
+pub fn node(fields: Value) -> swayipc::Node {
+    serde_json::from_value(with_defaults(fields)).unwrap()
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use crate::ipc::{StatsPeriod, UsageEntry, UsageStats};
+use crate::utils::now_ms;
+
+/// Spans older than this are only kept in the totals
+static SPANS_KEPT_MS: u64 = 25 * 3600 * 1000;
+
+/// Time during which a window had the focus
+struct Span {
+    app: String,
+    workspace: Option<String>,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Default, Clone)]
+struct Totals {
+    apps: HashMap<String, UsageEntry>,
+    workspaces: HashMap<String, UsageEntry>,
+}
+
+impl Totals {
+    fn add(&mut self, app: &str, workspace: Option<&str>, focused_ms: u64, switches: u32) {
+        let keys = [
+            (&mut self.apps, Some(app)),
+            (&mut self.workspaces, workspace),
+        ];
+        for (map, key) in keys {
+            if let Some(key) = key {
+                let e = map.entry(key.to_string()).or_insert_with(|| UsageEntry {
+                    name: key.to_string(),
+                    ..Default::default()
+                });
+                e.focused_ms += focused_ms;
+                e.switches += switches;
+            }
+        }
+    }
+
+    fn into_stats(self) -> UsageStats {
+        let sorted = |m: HashMap<String, UsageEntry>| {
+            let mut v: Vec<_> = m.into_values().collect();
+            v.sort_by(|a, b| b.focused_ms.cmp(&a.focused_ms).then(a.name.cmp(&b.name)));
+            v
+        };
+        UsageStats {
+            apps: sorted(self.apps),
+            workspaces: sorted(self.workspaces),
+        }
+    }
+}
+
+/// Focus time per application and workspace since the server started
+pub struct Usage {
+    started: u64,
+    /// Recent spans, oldest first
+    spans: Vec<Span>,
+    /// Span of the window which has the focus, `end` is not set yet
+    current: Option<Span>,
+    /// Spans which are no longer in `spans`
+    old: Totals,
+}
+
+impl Usage {
+    pub fn new() -> Usage {
+        Usage {
+            started: now_ms(),
+            spans: vec![],
+            current: None,
+            old: Totals::default(),
+        }
+    }
+
+    /// Account for a change of the focused window, with its application and
+    /// workspace, `None` when no window has the focus
+    pub fn focus(&mut self, window: Option<(String, Option<String>)>) {
+        self.focus_at(now_ms(), window);
+    }
+
+    fn focus_at(&mut self, now: u64, window: Option<(String, Option<String>)>) {
+        if let Some(mut span) = self.current.take() {
+            span.end = now;
+            self.spans.push(span);
+        }
+
+        let keep = self.spans.partition_point(|s| s.end + SPANS_KEPT_MS < now);
+        for s in self.spans.drain(..keep) {
+            self.old
+                .add(&s.app, s.workspace.as_deref(), s.end - s.start, 1);
+        }
+
+        self.current = window.map(|(app, workspace)| Span {
+            app,
+            workspace,
+            start: now,
+            end: now,
+        });
+    }
+
+    /// Focused time and number of switches over `period`
+    pub fn stats(&self, period: StatsPeriod) -> UsageStats {
+        self.stats_at(now_ms(), period)
+    }
+
+    fn stats_at(&self, now: u64, period: StatsPeriod) -> UsageStats {
+        let from = match period {
+            StatsPeriod::LastDay => now.saturating_sub(24 * 3600 * 1000),
+            StatsPeriod::LastHour => now.saturating_sub(3600 * 1000),
+            StatsPeriod::SinceStart => self.started,
+        };
+
+        let mut totals = if period == StatsPeriod::SinceStart {
+            self.old.clone()
+        } else {
+            Totals::default()
+        };
+
+        let current = self.current.as_ref().map(|s| Span {
+            app: s.app.clone(),
+            workspace: s.workspace.clone(),
+            start: s.start,
+            end: now,
+        });
+        for s in self.spans.iter().chain(current.as_ref()) {
+            if s.end < from {
+                continue;
+            }
+            let switches = if s.start >= from { 1 } else { 0 };
+            let focused = s.end - s.start.max(from);
+            totals.add(&s.app, s.workspace.as_deref(), focused, switches);
+        }
+
+        totals.into_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static HOUR: u64 = 3600 * 1000;
+    static MINUTE: u64 = 60 * 1000;
+
+    fn window(app: &str, workspace: &str) -> Option<(String, Option<String>)> {
+        Some((app.to_string(), Some(workspace.to_string())))
+    }
+
+    fn entry(entries: &[UsageEntry], name: &str) -> (u64, u32) {
+        entries
+            .iter()
+            .find(|e| e.name == name)
+            .map_or((0, 0), |e| (e.focused_ms, e.switches))
+    }
+
+    #[test]
+    fn stats_clip_to_period() {
+        let start = 100 * HOUR;
+        let mut usage = Usage {
+            started: start,
+            ..Usage::new()
+        };
+
+        // editor for 2 hours, then terminal for 30 minutes, still focused
+        usage.focus_at(start, window("editor", "1"));
+        usage.focus_at(start + 2 * HOUR, window("terminal", "2"));
+        let now = start + 2 * HOUR + 30 * MINUTE;
+
+        let hour = usage.stats_at(now, StatsPeriod::LastHour);
+        assert_eq!(entry(&hour.apps, "editor"), (30 * MINUTE, 0));
+        assert_eq!(entry(&hour.apps, "terminal"), (30 * MINUTE, 1));
+        assert_eq!(hour.apps[0].name, "editor");
+
+        let all = usage.stats_at(now, StatsPeriod::SinceStart);
+        assert_eq!(entry(&all.apps, "editor"), (2 * HOUR, 1));
+        assert_eq!(entry(&all.workspaces, "2"), (30 * MINUTE, 1));
+    }
+
+    #[test]
+    fn stats_keep_old_spans_in_totals() {
+        let start = 100 * HOUR;
+        let mut usage = Usage {
+            started: start,
+            ..Usage::new()
+        };
+
+        usage.focus_at(start, window("editor", "1"));
+        usage.focus_at(start + HOUR, None);
+        usage.focus_at(start + 30 * HOUR, window("editor", "1"));
+        let now = start + 30 * HOUR + MINUTE;
+
+        assert!(usage.spans.is_empty());
+        let all = usage.stats_at(now, StatsPeriod::SinceStart);
+        assert_eq!(entry(&all.apps, "editor"), (HOUR + MINUTE, 2));
+        let hour = usage.stats_at(now, StatsPeriod::LastHour);
+        assert_eq!(entry(&hour.apps, "editor"), (MINUTE, 1));
+        let day = usage.stats_at(now, StatsPeriod::LastDay);
+        assert_eq!(entry(&day.apps, "editor"), (MINUTE, 1));
+    }
+}